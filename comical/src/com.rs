@@ -11,6 +11,8 @@ use wio::com::ComPtr;
 use check_api_hr;
 use error::{check_hresult, LabelErrorHResult, Result};
 
+pub use com_object::{ComClass, ComObject};
+
 pub fn getter<I, F>(closure: F) -> result::Result<ComPtr<I>, HRESULT>
 where
     I: Interface,
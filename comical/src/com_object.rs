@@ -0,0 +1,480 @@
+// COM objects implemented in Rust, such as callbacks passed to other COM objects.
+//
+// A `ComObject<T>` is a vtable pointer, followed by a reference count and the object's data. It
+// provides the `IUnknown` methods, which go at the start of the vtable, and the object is freed
+// when the last reference is released.
+//
+// Rather than writing the vtable by hand, use `com_class!` to generate it.
+//
+// This only needs the ABI types, so off Windows there are stand-ins with the same layout, which
+// lets the reference counting be tested anywhere.
+
+use std::ptr::null_mut;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use self::abi::*;
+
+/// The data of a `ComObject`.
+pub trait ComClass: Send + Sync + Sized {
+    /// The interface's vtable. It must start with the `IUnknown` methods of `ComObject<Self>`,
+    /// and the other methods will be called with a pointer to the `ComObject<Self>`.
+    type Vtbl: 'static;
+
+    fn vtbl() -> &'static Self::Vtbl;
+
+    /// IIDs that `QueryInterface` succeeds for, other than `IUnknown`.
+    const INTERFACES: &'static [fn() -> GUID];
+}
+
+#[repr(C)]
+pub struct ComObject<T: ComClass> {
+    vtbl: &'static T::Vtbl,
+    refcount: AtomicUsize,
+    data: T,
+}
+
+impl<T: ComClass> ComObject<T> {
+    /// Create an object holding one reference, which is owned by the caller.
+    pub fn create(data: T) -> *mut IUnknown {
+        let object = Box::new(ComObject {
+            vtbl: T::vtbl(),
+            refcount: AtomicUsize::new(1),
+            data,
+        });
+        Box::into_raw(object) as *mut IUnknown
+    }
+
+    /// The data of the object that `this` points to.
+    ///
+    /// # Safety
+    ///
+    /// `this` must point to a live `ComObject<T>`, for instance the `this` passed to one of its
+    /// methods.
+    pub unsafe fn data<'a, I>(this: *mut I) -> &'a T {
+        &(*(this as *mut ComObject<T>)).data
+    }
+
+    // The `IUnknown` methods are only meant to be called through the vtable.
+
+    /// # Safety
+    ///
+    /// `this` must point to a live `ComObject<T>`.
+    pub unsafe extern "system" fn query_interface(
+        this: *mut IUnknown,
+        riid: REFIID,
+        obj: *mut *mut c_void,
+    ) -> HRESULT {
+        if obj.is_null() || riid.is_null() {
+            return E_POINTER;
+        }
+
+        let iid = &*riid;
+        if guid_eq(iid, &IUnknown::uuidof()) || T::INTERFACES.iter().any(|i| guid_eq(iid, &i())) {
+            ComObject::<T>::add_ref(this);
+            *obj = this as *mut c_void;
+            S_OK
+        } else {
+            *obj = null_mut();
+            E_NOINTERFACE
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `this` must point to a live `ComObject<T>`.
+    pub unsafe extern "system" fn add_ref(this: *mut IUnknown) -> ULONG {
+        let object = &*(this as *mut ComObject<T>);
+        (object.refcount.fetch_add(1, Ordering::Relaxed) + 1) as ULONG
+    }
+
+    /// # Safety
+    ///
+    /// `this` must point to a live `ComObject<T>`, and the caller must own a reference.
+    pub unsafe extern "system" fn release(this: *mut IUnknown) -> ULONG {
+        let object = this as *mut ComObject<T>;
+        let refcount = (*object).refcount.fetch_sub(1, Ordering::Release) - 1;
+        if refcount == 0 {
+            // Make sure all uses from other threads are done before dropping.
+            fence(Ordering::Acquire);
+            drop(Box::from_raw(object));
+        }
+        refcount as ULONG
+    }
+}
+
+fn guid_eq(a: &GUID, b: &GUID) -> bool {
+    a.Data1 == b.Data1 && a.Data2 == b.Data2 && a.Data3 == b.Data3 && a.Data4 == b.Data4
+}
+
+// The types used here and by `com_class!`.
+#[cfg(windows)]
+#[doc(hidden)]
+pub mod abi {
+    pub use winapi::ctypes::c_void;
+    pub use winapi::shared::guiddef::{GUID, REFIID};
+    pub use winapi::shared::ntdef::ULONG;
+    pub use winapi::shared::winerror::{E_NOINTERFACE, E_POINTER, E_UNEXPECTED, HRESULT, S_OK};
+    pub use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
+    pub use winapi::Interface;
+}
+
+#[cfg(not(windows))]
+#[doc(hidden)]
+#[allow(non_camel_case_types, non_snake_case)]
+pub mod abi {
+    pub use std::os::raw::c_void;
+
+    #[derive(Clone, Copy, Debug)]
+    #[repr(C)]
+    pub struct GUID {
+        pub Data1: u32,
+        pub Data2: u16,
+        pub Data3: u16,
+        pub Data4: [u8; 8],
+    }
+
+    pub type REFIID = *const GUID;
+    pub type HRESULT = i32;
+    pub type ULONG = u32;
+
+    pub const S_OK: HRESULT = 0;
+    pub const E_NOINTERFACE: HRESULT = 0x8000_4002u32 as HRESULT;
+    pub const E_POINTER: HRESULT = 0x8000_4003u32 as HRESULT;
+    pub const E_UNEXPECTED: HRESULT = 0x8000_ffffu32 as HRESULT;
+
+    /// # Safety
+    ///
+    /// The implementing type must be a COM interface, starting with a vtable pointer.
+    pub unsafe trait Interface {
+        fn uuidof() -> GUID;
+    }
+
+    #[repr(C)]
+    pub struct IUnknown {
+        pub lpVtbl: *const IUnknownVtbl,
+    }
+
+    unsafe impl Interface for IUnknown {
+        fn uuidof() -> GUID {
+            GUID {
+                Data1: 0x0000_0000,
+                Data2: 0x0000,
+                Data3: 0x0000,
+                Data4: [0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x46],
+            }
+        }
+    }
+
+    #[repr(C)]
+    pub struct IUnknownVtbl {
+        pub QueryInterface: unsafe extern "system" fn(
+            This: *mut IUnknown,
+            riid: REFIID,
+            ppvObject: *mut *mut c_void,
+        ) -> HRESULT,
+        pub AddRef: unsafe extern "system" fn(This: *mut IUnknown) -> ULONG,
+        pub Release: unsafe extern "system" fn(This: *mut IUnknown) -> ULONG,
+    }
+}
+
+/// Implement `ComClass` for a type, generating its vtable.
+///
+/// The vtable is described from the most derived interface down to `IUnknownVtbl`, giving the
+/// vtable type, the interface type that is `this` for its methods, and the methods added at that
+/// level. Each method is forwarded to a method of the class, which takes `&self` and the rest
+/// of the arguments, and returns an `HRESULT`. If it panics, the panic is caught and
+/// `E_UNEXPECTED` is returned.
+///
+/// `interfaces` lists what `QueryInterface` succeeds for (in addition to `IUnknown`), which is
+/// usually every interface in the vtable.
+///
+/// ```ignore
+/// com_class! {
+///     impl BackgroundCopyCallback {
+///         interfaces: [IBackgroundCopyCallback],
+///         vtbl: IBackgroundCopyCallbackVtbl for IBackgroundCopyCallback {
+///             parent: IUnknownVtbl,
+///             JobTransferred => job_transferred(job: *mut IBackgroundCopyJob),
+///             JobModification => job_modification(job: *mut IBackgroundCopyJob, reserved: DWORD),
+///         }
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! com_class {
+    (
+        impl $class:ident {
+            interfaces: [$($interface:ident),+ $(,)*],
+            vtbl: $vtbl:ident for $($vtbl_rest:tt)+
+        }
+    ) => {
+        impl $crate::com_object::ComClass for $class {
+            type Vtbl = $vtbl;
+
+            fn vtbl() -> &'static $vtbl {
+                static VTBL: $vtbl = $crate::com_class!(@vtbl $class; $vtbl for $($vtbl_rest)+);
+                &VTBL
+            }
+
+            const INTERFACES: &'static [fn() -> $crate::com_object::abi::GUID] =
+                &[$(<$interface as $crate::com_object::abi::Interface>::uuidof),+];
+        }
+    };
+
+    (@vtbl $class:ident; IUnknownVtbl) => {
+        $crate::com_object::abi::IUnknownVtbl {
+            QueryInterface: $crate::com_object::ComObject::<$class>::query_interface,
+            AddRef: $crate::com_object::ComObject::<$class>::add_ref,
+            Release: $crate::com_object::ComObject::<$class>::release,
+        }
+    };
+
+    (
+        @vtbl $class:ident;
+        $vtbl:ident for $interface:ident {
+            parent: $parent:ident $(for $parent_interface:ident $parent_body:tt)*,
+            $($method:ident => $class_method:ident($($arg:ident: $arg_ty:ty),* $(,)*)),* $(,)*
+        }
+    ) => {
+        $vtbl {
+            parent: $crate::com_class!(
+                @vtbl $class; $parent $(for $parent_interface $parent_body)*
+            ),
+            $($method: {
+                unsafe extern "system" fn method(
+                    this: *mut $interface,
+                    $($arg: $arg_ty),*
+                ) -> $crate::com_object::abi::HRESULT {
+                    let object = $crate::com_object::ComObject::<$class>::data(this);
+                    let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+                        object.$class_method($($arg),*)
+                    }));
+                    match result {
+                        Ok(hr) => hr,
+                        Err(_) => $crate::com_object::abi::E_UNEXPECTED,
+                    }
+                }
+                method
+            },)*
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+
+    struct Canary {
+        dropped: Arc<AtomicBool>,
+        pokes: AtomicUsize,
+    }
+
+    impl Canary {
+        fn poke(&self, count: u32) -> HRESULT {
+            assert!(count != 0);
+            self.pokes.fetch_add(count as usize, Ordering::SeqCst);
+            S_OK
+        }
+
+        fn prod(&self) -> HRESULT {
+            self.poke(10)
+        }
+    }
+
+    impl Drop for Canary {
+        fn drop(&mut self) {
+            assert!(!self.dropped.swap(true, Ordering::SeqCst));
+        }
+    }
+
+    // A made up interface, and another derived from it.
+
+    #[allow(non_snake_case)]
+    #[repr(C)]
+    struct ICanary {
+        lpVtbl: *const ICanaryVtbl,
+    }
+
+    #[allow(non_snake_case)]
+    #[repr(C)]
+    struct ICanaryVtbl {
+        parent: IUnknownVtbl,
+        Poke: unsafe extern "system" fn(This: *mut ICanary, count: u32) -> HRESULT,
+    }
+
+    unsafe impl Interface for ICanary {
+        fn uuidof() -> GUID {
+            GUID {
+                Data1: 0x1234_5678,
+                Data2: 0x9abc,
+                Data3: 0xdef0,
+                Data4: [1, 2, 3, 4, 5, 6, 7, 8],
+            }
+        }
+    }
+
+    #[allow(non_snake_case)]
+    #[repr(C)]
+    struct ICanary2 {
+        lpVtbl: *const ICanary2Vtbl,
+    }
+
+    #[allow(non_snake_case)]
+    #[repr(C)]
+    struct ICanary2Vtbl {
+        parent: ICanaryVtbl,
+        Prod: unsafe extern "system" fn(This: *mut ICanary2) -> HRESULT,
+    }
+
+    unsafe impl Interface for ICanary2 {
+        fn uuidof() -> GUID {
+            GUID {
+                Data1: 0x1234_5678,
+                Data2: 0x9abc,
+                Data3: 0xdef0,
+                Data4: [1, 2, 3, 4, 5, 6, 7, 9],
+            }
+        }
+    }
+
+    com_class! {
+        impl Canary {
+            interfaces: [ICanary, ICanary2],
+            vtbl: ICanary2Vtbl for ICanary2 {
+                parent: ICanaryVtbl for ICanary {
+                    parent: IUnknownVtbl,
+                    Poke => poke(count: u32),
+                },
+                Prod => prod(),
+            }
+        }
+    }
+
+    fn new_canary() -> (*mut IUnknown, Arc<AtomicBool>) {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let obj = ComObject::create(Canary {
+            dropped: dropped.clone(),
+            pokes: AtomicUsize::new(0),
+        });
+        (obj, dropped)
+    }
+
+    // Call through the vtable, as a COM client would.
+
+    unsafe fn query_interface(this: *mut IUnknown, iid: &GUID) -> (HRESULT, *mut c_void) {
+        let mut obj = null_mut();
+        let hr = ((*(*this).lpVtbl).QueryInterface)(this, iid, &mut obj);
+        (hr, obj)
+    }
+
+    unsafe fn add_ref(this: *mut IUnknown) -> ULONG {
+        ((*(*this).lpVtbl).AddRef)(this)
+    }
+
+    unsafe fn release(this: *mut IUnknown) -> ULONG {
+        ((*(*this).lpVtbl).Release)(this)
+    }
+
+    #[test]
+    fn refcount() {
+        let (obj, dropped) = new_canary();
+        unsafe {
+            assert_eq!(add_ref(obj), 2);
+            assert_eq!(add_ref(obj), 3);
+            assert_eq!(release(obj), 2);
+            assert_eq!(release(obj), 1);
+            assert!(!dropped.load(Ordering::SeqCst));
+            assert!(Arc::ptr_eq(
+                &ComObject::<Canary>::data(obj).dropped,
+                &dropped
+            ));
+
+            assert_eq!(release(obj), 0);
+        }
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn query_interfaces() {
+        let (obj, dropped) = new_canary();
+        unsafe {
+            let (hr, unknown) = query_interface(obj, &IUnknown::uuidof());
+            assert_eq!(hr, S_OK);
+            assert_eq!(unknown, obj as *mut c_void);
+
+            let (hr, canary) = query_interface(obj, &ICanary::uuidof());
+            assert_eq!(hr, S_OK);
+            assert_eq!(canary, obj as *mut c_void);
+
+            let (hr, canary2) = query_interface(obj, &ICanary2::uuidof());
+            assert_eq!(hr, S_OK);
+            assert_eq!(canary2, obj as *mut c_void);
+
+            let mut other_iid = ICanary::uuidof();
+            other_iid.Data4[7] = 0;
+            let (hr, other) = query_interface(obj, &other_iid);
+            assert_eq!(hr, E_NOINTERFACE);
+            assert!(other.is_null());
+
+            // Only the successful queries added references.
+            assert_eq!(release(obj), 3);
+            assert_eq!(release(obj), 2);
+            assert_eq!(release(obj), 1);
+            assert_eq!(release(obj), 0);
+        }
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn methods() {
+        let (obj, dropped) = new_canary();
+        unsafe {
+            let canary = obj as *mut ICanary2;
+            let vtbl = &*(*canary).lpVtbl;
+            let data = ComObject::<Canary>::data(obj);
+            assert_eq!((vtbl.parent.Poke)(canary as *mut ICanary, 2), S_OK);
+            assert_eq!((vtbl.Prod)(canary), S_OK);
+            assert_eq!(data.pokes.load(Ordering::SeqCst), 12);
+
+            // Panics don't unwind into the caller.
+            assert_eq!((vtbl.parent.Poke)(canary as *mut ICanary, 0), E_UNEXPECTED);
+            assert_eq!(data.pokes.load(Ordering::SeqCst), 12);
+
+            assert_eq!(release(obj), 0);
+        }
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn threaded_refcount() {
+        let (obj, dropped) = new_canary();
+        let addr = obj as usize;
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                unsafe { add_ref(obj) };
+                thread::spawn(move || {
+                    let obj = addr as *mut IUnknown;
+                    for _ in 0..1000 {
+                        unsafe {
+                            add_ref(obj);
+                            release(obj);
+                        }
+                    }
+                    unsafe { release(obj) };
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(!dropped.load(Ordering::SeqCst));
+        assert_eq!(unsafe { release(obj) }, 0);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
@@ -1,17 +1,17 @@
 use std::fmt;
 use std::result;
 
-use winapi::shared::minwindef::DWORD;
-use winapi::shared::winerror::{HRESULT, SUCCEEDED};
+#[cfg(windows)]
 use winapi::um::errhandlingapi::GetLastError;
 
 // TODO: This should probably use error_chain, to attach messages to underlying API errors.
 
+// `DWORD` and `HRESULT` are spelled out, so that errors can be used off Windows.
 #[derive(Debug)]
 pub enum ErrorCode {
     None,
-    DWord(DWORD),
-    HResult(HRESULT),
+    DWord(u32),
+    HResult(i32),
 }
 
 #[derive(Debug)]
@@ -20,6 +20,13 @@ pub struct FileLine(&'static str, u32);
 #[derive(Debug)]
 pub enum Error {
     Api(&'static str, ErrorCode, Option<FileLine>),
+    /// The other end of a pipe or connection closed it.
+    Disconnected,
+    /// A message was longer than the connection allows.
+    MessageTooLong {
+        len: usize,
+        max: usize,
+    },
     Message(String),
 }
 
@@ -31,13 +38,19 @@ impl fmt::Display for Error {
                     None => {}
                     Some(FileLine(file, line)) => write!(f, "{}:{} ", file, line)?,
                 };
-                write!(f, "{} failed.", api);
+                write!(f, "{} failed.", api)?;
                 match ec {
                     ErrorCode::None => {}
                     ErrorCode::DWord(rc) => write!(f, " rc = {:#010x}", rc)?,
                     ErrorCode::HResult(hr) => write!(f, " hr = {:#010x}", hr)?,
                 };
             }
+            Error::Disconnected => f.write_str("disconnected")?,
+            Error::MessageTooLong { len, max } => write!(
+                f,
+                "message of {} bytes is longer than the maximum of {}",
+                len, max
+            )?,
             Error::Message(ref msg) => f.write_str(msg)?,
         }
 
@@ -53,8 +66,9 @@ impl From<Error> for String {
 
 pub type Result<T> = result::Result<T, Error>;
 
-pub fn check_hresult(hr: HRESULT) -> result::Result<HRESULT, HRESULT> {
-    if !SUCCEEDED(hr) {
+pub fn check_hresult(hr: i32) -> result::Result<i32, i32> {
+    // Same as `SUCCEEDED`.
+    if hr < 0 {
         Err(hr)
     } else {
         Ok(hr)
@@ -62,7 +76,8 @@ pub fn check_hresult(hr: HRESULT) -> result::Result<HRESULT, HRESULT> {
 }
 
 /// for functions that set last error and return false (0) on failure
-pub fn check_nonzero<T>(rc: T) -> result::Result<T, DWORD>
+#[cfg(windows)]
+pub fn check_nonzero<T>(rc: T) -> result::Result<T, u32>
 where
     T: Eq,
     T: From<bool>,
@@ -112,7 +127,7 @@ pub trait LabelErrorDWord<T> {
     fn map_api_rc_file_line(self, api: &'static str, file: &'static str, line: u32) -> Result<T>;
 }
 
-impl<T> LabelErrorDWord<T> for result::Result<T, DWORD> {
+impl<T> LabelErrorDWord<T> for result::Result<T, u32> {
     fn map_api_rc(self, api: &'static str) -> Result<T> {
         self.map_err(|rc| Error::Api(api, ErrorCode::DWord(rc), None))
     }
@@ -142,7 +157,7 @@ pub trait LabelErrorHResult<T> {
     fn map_api_hr_file_line(self, api: &'static str, file: &'static str, line: u32) -> Result<T>;
 }
 
-impl<T> LabelErrorHResult<T> for result::Result<T, HRESULT> {
+impl<T> LabelErrorHResult<T> for result::Result<T, i32> {
     fn map_api_hr(self, api: &'static str) -> Result<T> {
         self.map_err(|hr| Error::Api(api, ErrorCode::HResult(hr), None))
     }
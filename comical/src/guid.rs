@@ -1,84 +1,301 @@
-use std::ffi::OsString;
-use std::fmt::{Debug, Display, Error, Formatter, Result};
-use std::iter;
-use std::mem::{size_of, transmute, transmute_copy, uninitialized};
+use std::cmp::Ordering;
+use std::error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
 use std::result;
 use std::str::FromStr;
 
-use winapi::ctypes::c_int;
-use winapi::shared::guiddef::GUID;
-use winapi::um::combaseapi::{CLSIDFromString, StringFromGUID2};
-use wio::wide::{FromWide, ToWide};
+use rand;
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use error::{check_hresult, LabelErrorHResult};
-
-const GUID_STRING_CHARACTERS: usize = 38;
+use com_object::abi::GUID;
 
 #[derive(Clone)]
 #[repr(transparent)]
 pub struct Guid(pub GUID);
 
-// TODO: I don't know if this is actually valid given padding, do something safer field-by-field?
-pub type GuidBuf = [u8; size_of::<GUID>()];
+impl Guid {
+    /// Generate a random (version 4) GUID.
+    pub fn new_v4() -> Guid {
+        let mut bytes = rand::random::<[u8; 16]>();
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Guid::from_bytes(bytes)
+    }
+
+    /// Build a GUID from bytes in the order they appear in the string form.
+    pub fn from_bytes(b: [u8; 16]) -> Guid {
+        let mut data4 = [0; 8];
+        data4.copy_from_slice(&b[8..]);
+        Guid(GUID {
+            Data1: (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32,
+            Data2: (b[4] as u16) << 8 | b[5] as u16,
+            Data3: (b[6] as u16) << 8 | b[7] as u16,
+            Data4: data4,
+        })
+    }
+
+    /// The bytes of the GUID in the order they appear in the string form.
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let g = &self.0;
+        let mut b = [0; 16];
+        b[0] = (g.Data1 >> 24) as u8;
+        b[1] = (g.Data1 >> 16) as u8;
+        b[2] = (g.Data1 >> 8) as u8;
+        b[3] = g.Data1 as u8;
+        b[4] = (g.Data2 >> 8) as u8;
+        b[5] = g.Data2 as u8;
+        b[6] = (g.Data3 >> 8) as u8;
+        b[7] = g.Data3 as u8;
+        b[8..].copy_from_slice(&g.Data4);
+        b
+    }
+
+    fn fields(&self) -> (u32, u16, u16, [u8; 8]) {
+        (self.0.Data1, self.0.Data2, self.0.Data3, self.0.Data4)
+    }
+}
 
 impl PartialEq for Guid {
     fn eq(&self, other: &Guid) -> bool {
-        unsafe { transmute_copy::<Guid, GuidBuf>(self) == transmute_copy::<Guid, GuidBuf>(other) }
+        self.fields() == other.fields()
     }
 }
 
 impl Eq for Guid {}
 
+impl Hash for Guid {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.fields().hash(state)
+    }
+}
+
+impl PartialOrd for Guid {
+    fn partial_cmp(&self, other: &Guid) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Field order matches the string form, so this sorts the same as the formatted GUIDs.
+impl Ord for Guid {
+    fn cmp(&self, other: &Guid) -> Ordering {
+        self.fields().cmp(&other.fields())
+    }
+}
+
 impl Debug for Guid {
-    fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "{:?}", unsafe {
-            &transmute::<Guid, GuidBuf>(self.clone())
-        })
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Guid({})", self)
     }
 }
 
+/// Formats as `{XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX}`, the same as `StringFromGUID2`.
 impl Display for Guid {
-    fn fmt(&self, f: &mut Formatter) -> Result {
-        let mut s: [u16; GUID_STRING_CHARACTERS + 1] = unsafe { uninitialized() };
-
-        let len = unsafe {
-            StringFromGUID2(
-                &(*self).0 as *const _ as *mut _,
-                s.as_mut_ptr(),
-                s.len() as c_int,
-            )
-        };
-        if len <= 0 {
-            return Err(Error);
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let b = self.to_bytes();
+        f.write_str("{")?;
+        for (i, byte) in b.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02X}", byte)?;
         }
+        f.write_str("}")
+    }
+}
 
-        let s = &s[..len as usize];
-        if let Ok(s) = OsString::from_wide_null(&s).into_string() {
-            f.write_str(&s)
-        } else {
-            Err(Error)
-        }
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseGuidError;
+
+impl Display for ParseGuidError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("invalid GUID")
+    }
+}
+
+impl error::Error for ParseGuidError {}
+
+impl From<ParseGuidError> for String {
+    fn from(error: ParseGuidError) -> Self {
+        error.to_string()
     }
 }
 
+/// Accepts 32 hex digits, optionally hyphenated as 8-4-4-4-12 and optionally in braces,
+/// in either case.
 impl FromStr for Guid {
-    type Err = ::error::Error;
+    type Err = ParseGuidError;
 
     fn from_str(s: &str) -> result::Result<Self, Self::Err> {
-        let mut guid = unsafe { uninitialized() };
-
-        let s = if s.chars().next() == Some('{') {
-            s.to_wide_null()
+        let s = if s.starts_with('{') && s.ends_with('}') && s.len() >= 2 {
+            &s[1..s.len() - 1]
         } else {
-            iter::once(b'{' as u16)
-                .chain(s.to_wide().into_iter())
-                .chain(Some(b'}' as u16))
-                .chain(Some(0))
-                .collect()
+            s
         };
 
-        check_hresult(unsafe { CLSIDFromString(s.as_ptr(), &mut guid) })
-            .map_api_hr("CLSIDFromString")?;
-        Ok(Guid(guid))
+        let hyphenated = match s.len() {
+            32 => false,
+            36 => true,
+            _ => return Err(ParseGuidError),
+        };
+
+        let mut bytes = [0u8; 16];
+        let mut digits = 0;
+        for (i, c) in s.bytes().enumerate() {
+            if hyphenated && (i == 8 || i == 13 || i == 18 || i == 23) {
+                if c != b'-' {
+                    return Err(ParseGuidError);
+                }
+                continue;
+            }
+            let nibble = match c {
+                b'0'..=b'9' => c - b'0',
+                b'a'..=b'f' => c - b'a' + 10,
+                b'A'..=b'F' => c - b'A' + 10,
+                _ => return Err(ParseGuidError),
+            };
+            bytes[digits / 2] = bytes[digits / 2] << 4 | nibble;
+            digits += 1;
+        }
+
+        Ok(Guid::from_bytes(bytes))
+    }
+}
+
+// Human-readable formats get the string form; binary formats get the fields in order, the same
+// layout as the remote derive that `bitstask` used to carry.
+impl Serialize for Guid {
+    fn serialize<S>(&self, serializer: S) -> result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            self.fields().serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Guid {
+    fn deserialize<D>(deserializer: D) -> result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(D::Error::custom)
+        } else {
+            let (data1, data2, data3, data4) = Deserialize::deserialize(deserializer)?;
+            Ok(Guid(GUID {
+                Data1: data1,
+                Data2: data2,
+                Data3: data3,
+                Data4: data4,
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    use bincode;
+    use serde_json;
+
+    const GUID_STR: &str = "{6B29FC40-CA47-1067-B31D-00DD010662DA}";
+
+    fn sample() -> Guid {
+        Guid(GUID {
+            Data1: 0x6B29_FC40,
+            Data2: 0xCA47,
+            Data3: 0x1067,
+            Data4: [0xB3, 0x1D, 0x00, 0xDD, 0x01, 0x06, 0x62, 0xDA],
+        })
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(sample().to_string(), GUID_STR);
+        assert_eq!(format!("{:?}", sample()), format!("Guid({})", GUID_STR));
+    }
+
+    #[test]
+    fn parse() {
+        for s in &[
+            GUID_STR,
+            "6B29FC40-CA47-1067-B31D-00DD010662DA",
+            "6b29fc40-ca47-1067-b31d-00dd010662da",
+            "{6b29fc40ca471067b31d00dd010662da}",
+            "6B29FC40CA471067B31D00DD010662DA",
+        ] {
+            assert_eq!(s.parse::<Guid>(), Ok(sample()), "{}", s);
+        }
+
+        for s in &[
+            "",
+            "{}",
+            "{6B29FC40-CA47-1067-B31D-00DD010662DA",
+            "6B29FC40-CA47-1067-B31D-00DD010662DA}",
+            "6B29FC40-CA47-1067-B31D-00DD010662D",
+            "6B29FC40-CA47-1067-B31D-00DD010662DAA",
+            "6B29FC40+CA47-1067-B31D-00DD010662DA",
+            "6B29FC4-0CA47-1067-B31D-00DD010662DA",
+            "6B29FC40-CA47-1067-B31D-00DD010662DG",
+            "+B29FC40CA471067B31D00DD010662DA",
+            "6B29FC40-CA47-1067-B31D-00DD0106é",
+        ] {
+            assert_eq!(s.parse::<Guid>(), Err(ParseGuidError), "{}", s);
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let bytes = sample().to_bytes();
+        assert_eq!(bytes[..4], [0x6B, 0x29, 0xFC, 0x40]);
+        assert_eq!(Guid::from_bytes(bytes), sample());
+    }
+
+    #[test]
+    fn ordering() {
+        let mut a = sample();
+        let mut b = sample();
+        a.0.Data1 = 1;
+        b.0.Data2 = 0xFFFF;
+        assert!(a < b);
+        assert!(a.to_string() < b.to_string());
+        assert_eq!(sample().cmp(&sample()), Ordering::Equal);
+
+        let set: HashSet<Guid> = vec![sample(), a.clone(), sample()].into_iter().collect();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn new_v4() {
+        let a = Guid::new_v4();
+        let b = Guid::new_v4();
+        assert_ne!(a, b);
+        for guid in &[a, b] {
+            let s = guid.to_string();
+            assert_eq!(&s[15..16], "4");
+            assert!("89AB".contains(&s[20..21]));
+            assert_eq!(s.parse::<Guid>().as_ref(), Ok(guid));
+        }
+    }
+
+    #[test]
+    fn serde() {
+        let json = serde_json::to_string(&sample()).unwrap();
+        assert_eq!(json, format!("\"{}\"", GUID_STR));
+        assert_eq!(serde_json::from_str::<Guid>(&json).unwrap(), sample());
+        assert!(serde_json::from_str::<Guid>("\"not a guid\"").is_err());
+
+        let bin = bincode::serialize(&sample()).unwrap();
+        assert_eq!(bin.len(), 16);
+        assert_eq!(bincode::deserialize::<Guid>(&bin).unwrap(), sample());
     }
 }
@@ -1,10 +1,24 @@
+extern crate rand;
+extern crate serde;
 extern crate winapi;
 extern crate wio;
 
+#[cfg(test)]
+extern crate bincode;
+#[cfg(test)]
+extern crate serde_json;
+
+// Everything except `com_object`, `error` and `guid` needs Windows.
+#[cfg(windows)]
 pub mod bstr;
+#[cfg(windows)]
 pub mod com;
+pub mod com_object;
 pub mod error;
 pub mod guid;
+#[cfg(windows)]
 pub mod handle;
+#[cfg(windows)]
 pub mod safearray;
+#[cfg(windows)]
 pub mod variant;
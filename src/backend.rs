@@ -0,0 +1,69 @@
+use std::ffi::{OsStr, OsString};
+use std::panic::RefUnwindSafe;
+
+use comical::error::Result;
+use comical::guid::Guid;
+
+use protocol::{BitsJobError, BitsJobStatus, CompleteResult, JobPriority, ProxySettings};
+
+// The server only talks to the download service through these traits, so that it can be run
+// against something other than BITS (see `sim`).
+
+pub type TransferredCallback<J> = (Fn(J) -> () + RefUnwindSafe + Send + Sync + 'static);
+pub type ErrorCallback<J> = (Fn(J, BitsJobError) -> () + RefUnwindSafe + Send + Sync + 'static);
+pub type ModificationCallback<J> = (Fn(J) -> () + RefUnwindSafe + Send + Sync + 'static);
+
+/// A service that creates and tracks download jobs.
+///
+/// Backends are cloned into each monitor thread, so cloning should be cheap.
+pub trait DownloadBackend: Clone + RefUnwindSafe + Send + 'static {
+    type Job: DownloadJob;
+
+    /// Held for as long as a thread (other than the main thread) is working with jobs.
+    type ThreadGuard;
+
+    /// Prepare the current thread to work with jobs.
+    fn init_thread(&self) -> Result<Self::ThreadGuard>;
+
+    fn create_job(&self, display_name: &OsStr) -> Result<Self::Job>;
+
+    fn get_job(&self, guid: &Guid) -> Result<Self::Job>;
+
+    /// All jobs owned by the current user.
+    fn list_jobs(&self) -> Result<Vec<Self::Job>>;
+}
+
+/// A single download job, with the lifecycle of a BITS job.
+pub trait DownloadJob: Sized {
+    fn guid(&self) -> Result<Guid>;
+
+    fn display_name(&self) -> Result<OsString>;
+
+    fn set_description(&mut self, description: &OsStr) -> Result<()>;
+
+    fn set_priority(&mut self, priority: JobPriority) -> Result<()>;
+
+    fn set_proxy_settings(&mut self, proxy: &ProxySettings) -> Result<()>;
+
+    fn add_file(&mut self, remote_url: &OsStr, local_file: &OsStr) -> Result<()>;
+
+    fn resume(&mut self) -> Result<()>;
+
+    fn suspend(&mut self) -> Result<()>;
+
+    /// Commit the transferred files to their save paths, this ends the job. If the job hasn't
+    /// finished transferring, only files that are complete are committed.
+    fn complete(&mut self) -> Result<CompleteResult>;
+
+    fn cancel(&mut self) -> Result<()>;
+
+    fn get_status(&mut self) -> Result<BitsJobStatus>;
+
+    /// Only one set of callbacks can be registered, registering again replaces them.
+    fn register_callbacks(
+        &mut self,
+        transferred: Option<Box<TransferredCallback<Self>>>,
+        error: Option<Box<ErrorCallback<Self>>>,
+        modification: Option<Box<ModificationCallback<Self>>>,
+    ) -> Result<()>;
+}
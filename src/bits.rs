@@ -1,22 +1,94 @@
-use std::ffi::OsStr;
-use std::mem;
+use std::ffi::{OsStr, OsString};
+use std::ptr::{null, null_mut};
 
-use comical::com::{create_instance_local_server, getter};
+use comical::com::{create_instance_local_server, getter, ComInited, ComObject};
 use comical::error::{check_hresult, LabelErrorHResult, Result};
 use comical::guid::Guid;
+use winapi::shared::guiddef::GUID;
+use winapi::shared::minwindef::FALSE;
+use winapi::shared::winerror::HRESULT;
 use winapi::um::bits::{
-    BackgroundCopyManager, IBackgroundCopyCallback, IBackgroundCopyError, IBackgroundCopyJob,
-    IBackgroundCopyManager, BG_JOB_PRIORITY, BG_JOB_STATE_ERROR, BG_JOB_STATE_TRANSIENT_ERROR,
+    BackgroundCopyManager, IBackgroundCopyError, IBackgroundCopyFile, IBackgroundCopyJob,
+    IBackgroundCopyManager, IEnumBackgroundCopyFiles, IEnumBackgroundCopyJobs, BG_ERROR_CONTEXT,
+    BG_ERROR_CONTEXT_GENERAL_QUEUE_MANAGER, BG_ERROR_CONTEXT_GENERAL_TRANSPORT,
+    BG_ERROR_CONTEXT_LOCAL_FILE, BG_ERROR_CONTEXT_NONE,
+    BG_ERROR_CONTEXT_QUEUE_MANAGER_NOTIFICATION, BG_ERROR_CONTEXT_REMOTE_APPLICATION,
+    BG_ERROR_CONTEXT_REMOTE_FILE, BG_ERROR_CONTEXT_UNKNOWN, BG_FILE_PROGRESS, BG_JOB_PRIORITY,
+    BG_JOB_PRIORITY_FOREGROUND, BG_JOB_PRIORITY_HIGH, BG_JOB_PRIORITY_LOW, BG_JOB_PRIORITY_NORMAL,
+    BG_JOB_PROGRESS, BG_JOB_PROXY_USAGE_NO_PROXY, BG_JOB_PROXY_USAGE_OVERRIDE,
+    BG_JOB_PROXY_USAGE_PRECONFIG, BG_JOB_STATE, BG_JOB_STATE_ACKNOWLEDGED, BG_JOB_STATE_CANCELLED,
+    BG_JOB_STATE_CONNECTING, BG_JOB_STATE_ERROR, BG_JOB_STATE_QUEUED, BG_JOB_STATE_SUSPENDED,
+    BG_JOB_STATE_TRANSFERRED, BG_JOB_STATE_TRANSFERRING, BG_JOB_STATE_TRANSIENT_ERROR,
     BG_JOB_TYPE_DOWNLOAD, BG_NOTIFY_JOB_ERROR, BG_NOTIFY_JOB_MODIFICATION,
     BG_NOTIFY_JOB_TRANSFERRED,
 };
-use winapi::um::unknwnbase::IUnknown;
+use winapi::um::bitsmsg::{BG_S_PARTIAL_COMPLETE, BG_S_UNABLE_TO_DELETE_FILES};
+use winapi::um::combaseapi::CoTaskMemFree;
+use winapi::um::winnt::LPWSTR;
 use wio::com::ComPtr;
-use wio::wide::ToWide;
+use wio::wide::{FromWide, ToWide};
 
 use comical::{call, get};
 
-use protocol::{BitsJobError, BitsJobStatus};
+use backend::{
+    DownloadBackend, DownloadJob, ErrorCallback, ModificationCallback, TransferredCallback,
+};
+use protocol::{
+    BitsFileProgress, BitsJobError, BitsJobStatus, CompleteResult, ErrorContext, HResult,
+    JobPriority, JobProgress, JobState, ProxySettings,
+};
+
+/// The real BITS service.
+#[derive(Clone)]
+pub struct BitsBackend;
+
+impl DownloadBackend for BitsBackend {
+    type Job = BitsJob;
+    type ThreadGuard = ComInited;
+
+    fn init_thread(&self) -> Result<ComInited> {
+        ComInited::init_mta()
+    }
+
+    fn create_job(&self, display_name: &OsStr) -> Result<BitsJob> {
+        BitsJob::new(display_name)
+    }
+
+    fn get_job(&self, guid: &Guid) -> Result<BitsJob> {
+        BitsJob::get_by_guid(guid)
+    }
+
+    fn list_jobs(&self) -> Result<Vec<BitsJob>> {
+        let bcm = connect_bcm()?;
+        let jobs = unsafe {
+            get!(
+                |e| bcm,
+                IBackgroundCopyManager::EnumJobs(
+                    0, // dwFlags, only the current user's jobs
+                    e,
+                )
+            )
+        }?;
+
+        let mut list = Vec::new();
+        loop {
+            let mut job = null_mut();
+            let mut fetched = 0;
+            unsafe {
+                call!(
+                    jobs,
+                    IEnumBackgroundCopyJobs::Next(1, &mut job, &mut fetched)
+                )
+            }?;
+            if fetched == 0 {
+                break;
+            }
+            list.push(unsafe { BitsJob::from_ptr(ComPtr::from_raw(job)) });
+        }
+
+        Ok(list)
+    }
+}
 
 pub fn connect_bcm() -> Result<ComPtr<IBackgroundCopyManager>> {
     create_instance_local_server::<BackgroundCopyManager, IBackgroundCopyManager>()
@@ -30,8 +102,8 @@ pub struct BitsJob {
 impl BitsJob {
     pub fn new(display_name: &OsStr) -> Result<Self> {
         let bcm = connect_bcm()?;
+        let mut guid = GUID::default();
         unsafe {
-            let mut guid = mem::uninitialized();
             let job = get!(
                 |job| bcm,
                 IBackgroundCopyManager::CreateJob(
@@ -58,73 +130,163 @@ impl BitsJob {
         BitsJob { job }
     }
 
-    pub fn guid(&self) -> Result<Guid> {
+    fn get_file_progress(&self) -> Result<Vec<BitsFileProgress>> {
+        let files = unsafe { get!(|e| self.job, IBackgroundCopyJob::EnumFiles(e)) }?;
+
+        let mut file_progress = Vec::new();
+        loop {
+            let mut file = null_mut();
+            let mut fetched = 0;
+            unsafe {
+                call!(
+                    files,
+                    IEnumBackgroundCopyFiles::Next(1, &mut file, &mut fetched)
+                )
+            }?;
+            if fetched == 0 {
+                break;
+            }
+            let file = unsafe { ComPtr::from_raw(file) };
+
+            let mut progress = BG_FILE_PROGRESS {
+                BytesTotal: 0,
+                BytesTransferred: 0,
+                Completed: FALSE,
+            };
+            unsafe { call!(file, IBackgroundCopyFile::GetProgress(&mut progress)) }?;
+
+            file_progress.push(BitsFileProgress {
+                bytes_total: progress.BytesTotal,
+                bytes_transferred: progress.BytesTransferred,
+                completed: progress.Completed != FALSE,
+            });
+        }
+
+        Ok(file_progress)
+    }
+
+    fn get_error(error_obj: ComPtr<IBackgroundCopyError>) -> Result<BitsJobError> {
+        let mut context = 0;
+        let mut hresult = 0;
         unsafe {
-            let mut guid = mem::uninitialized();
-            call!(self.job, IBackgroundCopyJob::GetId(&mut guid))?;
-            Ok(Guid(guid))
+            call!(
+                error_obj,
+                IBackgroundCopyError::GetError(&mut context, &mut hresult)
+            )
+        }?;
+
+        Ok(BitsJobError {
+            context: ErrorContext::from(context),
+            error: HResult::from(hresult),
+        })
+    }
+}
+
+impl DownloadJob for BitsJob {
+    fn guid(&self) -> Result<Guid> {
+        let mut guid = GUID::default();
+        unsafe { call!(self.job, IBackgroundCopyJob::GetId(&mut guid)) }?;
+        Ok(Guid(guid))
+    }
+
+    fn display_name(&self) -> Result<OsString> {
+        unsafe {
+            let mut name = null_mut();
+            call!(self.job, IBackgroundCopyJob::GetDisplayName(&mut name))?;
+            Ok(take_task_mem_string(name))
         }
     }
 
-    pub fn add_file(&mut self, remote_url: &OsStr, local_file: &OsStr) -> Result<()> {
+    fn set_description(&mut self, description: &OsStr) -> Result<()> {
         unsafe {
             call!(
                 self.job,
-                IBackgroundCopyJob::AddFile(
-                    remote_url.to_wide_null().as_ptr(),
-                    local_file.to_wide_null().as_ptr(),
-                )
+                IBackgroundCopyJob::SetDescription(description.to_wide_null().as_ptr())
             )
         }?;
         Ok(())
     }
 
-    pub fn set_description(&mut self, description: &OsStr) -> Result<()> {
+    fn set_priority(&mut self, priority: JobPriority) -> Result<()> {
+        let priority = BG_JOB_PRIORITY::from(priority);
+        unsafe { call!(self.job, IBackgroundCopyJob::SetPriority(priority)) }?;
+        Ok(())
+    }
+
+    fn set_proxy_settings(&mut self, proxy: &ProxySettings) -> Result<()> {
+        // The lists must be null unless overriding.
+        let (usage, proxy_list, bypass_list) = match proxy {
+            ProxySettings::Preconfig => (BG_JOB_PROXY_USAGE_PRECONFIG, None, None),
+            ProxySettings::NoProxy => (BG_JOB_PROXY_USAGE_NO_PROXY, None, None),
+            ProxySettings::Override {
+                proxy_list,
+                bypass_list,
+            } => (
+                BG_JOB_PROXY_USAGE_OVERRIDE,
+                Some(proxy_list.to_wide_null()),
+                bypass_list.as_ref().map(|l| l.to_wide_null()),
+            ),
+        };
+
         unsafe {
             call!(
                 self.job,
-                IBackgroundCopyJob::SetDescription(description.to_wide_null().as_ptr())
+                IBackgroundCopyJob::SetProxySettings(
+                    usage,
+                    proxy_list.as_ref().map_or(null(), |l| l.as_ptr()),
+                    bypass_list.as_ref().map_or(null(), |l| l.as_ptr()),
+                )
             )
         }?;
         Ok(())
     }
 
-    // TODO
-    //fn set_proxy()
-
-    pub fn set_priority(&mut self, priority: BG_JOB_PRIORITY) -> Result<()> {
-        unsafe { call!(self.job, IBackgroundCopyJob::SetPriority(priority)) }?;
+    fn add_file(&mut self, remote_url: &OsStr, local_file: &OsStr) -> Result<()> {
+        unsafe {
+            call!(
+                self.job,
+                IBackgroundCopyJob::AddFile(
+                    remote_url.to_wide_null().as_ptr(),
+                    local_file.to_wide_null().as_ptr(),
+                )
+            )
+        }?;
         Ok(())
     }
 
-    pub fn resume(&mut self) -> Result<()> {
+    fn resume(&mut self) -> Result<()> {
         unsafe { call!(self.job, IBackgroundCopyJob::Resume()) }?;
         Ok(())
     }
 
-    pub fn suspend(&mut self) -> Result<()> {
+    fn suspend(&mut self) -> Result<()> {
         unsafe { call!(self.job, IBackgroundCopyJob::Suspend()) }?;
         Ok(())
     }
 
-    pub fn complete(&mut self) -> Result<()> {
-        unsafe { call!(self.job, IBackgroundCopyJob::Complete()) }?;
-        // TODO need to handle partial completion
-        Ok(())
+    fn complete(&mut self) -> Result<CompleteResult> {
+        // Find out which files finished transferring, the job can't be queried once completed.
+        let files = self.get_file_progress()?;
+
+        let hr = unsafe { call!(self.job, IBackgroundCopyJob::Complete()) }?;
+        let partial = hr == BG_S_PARTIAL_COMPLETE as HRESULT;
+        Ok(CompleteResult {
+            committed: files.iter().map(|f| f.completed || !partial).collect(),
+            unable_to_delete_temp_files: hr == BG_S_UNABLE_TO_DELETE_FILES as HRESULT,
+        })
     }
 
-    pub fn cancel(&mut self) -> Result<()> {
+    fn cancel(&mut self) -> Result<()> {
         unsafe { call!(self.job, IBackgroundCopyJob::Cancel()) }?;
         Ok(())
     }
 
-    pub fn register_callbacks(
+    fn register_callbacks(
         &mut self,
-        transferred: Option<Box<callback::TransferredCallback>>,
-        error: Option<Box<callback::ErrorCallback>>,
-        modification: Option<Box<callback::ModificationCallback>>,
-    ) -> Result<()>
-where {
+        transferred: Option<Box<TransferredCallback<BitsJob>>>,
+        error: Option<Box<ErrorCallback<BitsJob>>>,
+        modification: Option<Box<ModificationCallback<BitsJob>>>,
+    ) -> Result<()> {
         // TODO check via GetNotifyInterface
         /*if self.callback.is_some() {
             return Err(Error::Message("callback already registered".to_string()));
@@ -151,30 +313,25 @@ where {
             )?;
         }
 
-        let callback = Box::new(callback::BackgroundCopyCallback {
-            interface: IBackgroundCopyCallback {
-                lpVtbl: &callback::VTBL,
-            },
+        let callback = ComObject::create(callback::BackgroundCopyCallback {
             transferred,
             error,
             modification,
         });
-
-        // TODO: don't just leak, proper ref counting
+        // BITS takes its own reference, ours is released when `callback` drops.
+        let callback = unsafe { ComPtr::from_raw(callback) };
         unsafe {
             call!(
                 self.job,
-                IBackgroundCopyJob::SetNotifyInterface(Box::leak(callback)
-                    as *mut callback::BackgroundCopyCallback
-                    as *mut IUnknown)
+                IBackgroundCopyJob::SetNotifyInterface(callback.as_raw())
             )?;
         }
         Ok(())
     }
 
-    pub fn get_status(&mut self) -> Result<BitsJobStatus> {
+    fn get_status(&mut self) -> Result<BitsJobStatus> {
         let mut state = 0;
-        let mut progress = unsafe { mem::uninitialized() };
+        let mut progress = BG_JOB_PROGRESS::default();
         let mut error_count = 0;
 
         unsafe {
@@ -186,11 +343,13 @@ where {
             )?;
         }
 
+        let state = JobState::from(state);
         Ok(BitsJobStatus {
             state,
-            progress,
+            progress: JobProgress::from(progress),
+            files: self.get_file_progress()?,
             error_count,
-            error: if state == BG_JOB_STATE_ERROR || state == BG_JOB_STATE_TRANSIENT_ERROR {
+            error: if state == JobState::Error || state == JobState::TransientError {
                 let error_obj = unsafe { get!(|e| self.job, IBackgroundCopyJob::GetError(e)) }?;
 
                 Some(BitsJob::get_error(error_obj)?)
@@ -199,160 +358,240 @@ where {
             },
         })
     }
+}
 
-    fn get_error(error_obj: ComPtr<IBackgroundCopyError>) -> Result<BitsJobError> {
-        let mut context = 0;
-        let mut hresult = 0;
-        unsafe {
-            call!(
-                error_obj,
-                IBackgroundCopyError::GetError(&mut context, &mut hresult)
-            )
-        }?;
+/// Copy a string returned by a COM call, then free it with `CoTaskMemFree`.
+unsafe fn take_task_mem_string(s: LPWSTR) -> OsString {
+    let string = OsString::from_wide_ptr_null(s);
+    CoTaskMemFree(s as *mut _);
+    string
+}
 
-        Ok(BitsJobError {
-            context,
-            error: hresult,
-        })
+// Conversions between the BITS types and the platform-neutral ones in `protocol`. Values that
+// `protocol` has no name for are kept as `Other`, so nothing is lost in either direction.
+
+impl From<BG_JOB_STATE> for JobState {
+    fn from(state: BG_JOB_STATE) -> Self {
+        match state {
+            BG_JOB_STATE_QUEUED => JobState::Queued,
+            BG_JOB_STATE_CONNECTING => JobState::Connecting,
+            BG_JOB_STATE_TRANSFERRING => JobState::Transferring,
+            BG_JOB_STATE_SUSPENDED => JobState::Suspended,
+            BG_JOB_STATE_ERROR => JobState::Error,
+            BG_JOB_STATE_TRANSIENT_ERROR => JobState::TransientError,
+            BG_JOB_STATE_TRANSFERRED => JobState::Transferred,
+            BG_JOB_STATE_ACKNOWLEDGED => JobState::Acknowledged,
+            BG_JOB_STATE_CANCELLED => JobState::Cancelled,
+            other => JobState::Other(other),
+        }
+    }
+}
+
+impl From<JobState> for BG_JOB_STATE {
+    fn from(state: JobState) -> Self {
+        match state {
+            JobState::Queued => BG_JOB_STATE_QUEUED,
+            JobState::Connecting => BG_JOB_STATE_CONNECTING,
+            JobState::Transferring => BG_JOB_STATE_TRANSFERRING,
+            JobState::Suspended => BG_JOB_STATE_SUSPENDED,
+            JobState::Error => BG_JOB_STATE_ERROR,
+            JobState::TransientError => BG_JOB_STATE_TRANSIENT_ERROR,
+            JobState::Transferred => BG_JOB_STATE_TRANSFERRED,
+            JobState::Acknowledged => BG_JOB_STATE_ACKNOWLEDGED,
+            JobState::Cancelled => BG_JOB_STATE_CANCELLED,
+            JobState::Other(other) => other,
+        }
+    }
+}
+
+impl From<BG_ERROR_CONTEXT> for ErrorContext {
+    fn from(context: BG_ERROR_CONTEXT) -> Self {
+        match context {
+            BG_ERROR_CONTEXT_NONE => ErrorContext::None,
+            BG_ERROR_CONTEXT_UNKNOWN => ErrorContext::Unknown,
+            BG_ERROR_CONTEXT_GENERAL_QUEUE_MANAGER => ErrorContext::GeneralQueueManager,
+            BG_ERROR_CONTEXT_QUEUE_MANAGER_NOTIFICATION => ErrorContext::QueueManagerNotification,
+            BG_ERROR_CONTEXT_LOCAL_FILE => ErrorContext::LocalFile,
+            BG_ERROR_CONTEXT_REMOTE_FILE => ErrorContext::RemoteFile,
+            BG_ERROR_CONTEXT_GENERAL_TRANSPORT => ErrorContext::GeneralTransport,
+            BG_ERROR_CONTEXT_REMOTE_APPLICATION => ErrorContext::RemoteApplication,
+            other => ErrorContext::Other(other),
+        }
+    }
+}
+
+impl From<ErrorContext> for BG_ERROR_CONTEXT {
+    fn from(context: ErrorContext) -> Self {
+        match context {
+            ErrorContext::None => BG_ERROR_CONTEXT_NONE,
+            ErrorContext::Unknown => BG_ERROR_CONTEXT_UNKNOWN,
+            ErrorContext::GeneralQueueManager => BG_ERROR_CONTEXT_GENERAL_QUEUE_MANAGER,
+            ErrorContext::QueueManagerNotification => BG_ERROR_CONTEXT_QUEUE_MANAGER_NOTIFICATION,
+            ErrorContext::LocalFile => BG_ERROR_CONTEXT_LOCAL_FILE,
+            ErrorContext::RemoteFile => BG_ERROR_CONTEXT_REMOTE_FILE,
+            ErrorContext::GeneralTransport => BG_ERROR_CONTEXT_GENERAL_TRANSPORT,
+            ErrorContext::RemoteApplication => BG_ERROR_CONTEXT_REMOTE_APPLICATION,
+            ErrorContext::Other(other) => other,
+        }
+    }
+}
+
+impl From<JobPriority> for BG_JOB_PRIORITY {
+    fn from(priority: JobPriority) -> Self {
+        match priority {
+            JobPriority::Foreground => BG_JOB_PRIORITY_FOREGROUND,
+            JobPriority::High => BG_JOB_PRIORITY_HIGH,
+            JobPriority::Normal => BG_JOB_PRIORITY_NORMAL,
+            JobPriority::Low => BG_JOB_PRIORITY_LOW,
+        }
+    }
+}
+
+impl From<BG_JOB_PROGRESS> for JobProgress {
+    fn from(progress: BG_JOB_PROGRESS) -> Self {
+        JobProgress {
+            bytes_total: progress.BytesTotal,
+            bytes_transferred: progress.BytesTransferred,
+            files_total: progress.FilesTotal,
+            files_transferred: progress.FilesTransferred,
+        }
+    }
+}
+
+impl From<HRESULT> for HResult {
+    fn from(hr: HRESULT) -> Self {
+        HResult(hr)
+    }
+}
+
+impl From<HResult> for HRESULT {
+    fn from(hr: HResult) -> Self {
+        hr.0
     }
 }
 
 mod callback {
-    use std::panic::{catch_unwind, RefUnwindSafe};
+    use std::any::Any;
+    use std::panic::catch_unwind;
 
-    use comical::guid::Guid;
-    use winapi::ctypes::c_void;
-    use winapi::shared::guiddef::REFIID;
+    use comical::com_class;
     use winapi::shared::minwindef::DWORD;
-    use winapi::shared::ntdef::ULONG;
-    use winapi::shared::winerror::{E_NOINTERFACE, HRESULT, NOERROR, S_OK};
+    use winapi::shared::winerror::{HRESULT, S_OK};
     use winapi::um::bits::{
         IBackgroundCopyCallback, IBackgroundCopyCallbackVtbl, IBackgroundCopyError,
         IBackgroundCopyJob,
     };
-    use winapi::um::unknwnbase::{IUnknown, IUnknownVtbl};
-    use winapi::Interface;
     use wio::com::ComPtr;
 
-    use bits::{BitsJob, BitsJobError};
+    use backend::{ErrorCallback, ModificationCallback, TransferredCallback};
+    use bits::BitsJob;
+    use logging::{self, LogContext};
 
-    pub type TransferredCallback = (Fn(BitsJob) -> () + RefUnwindSafe + Send + Sync + 'static);
-    pub type ErrorCallback =
-        (Fn(BitsJob, BitsJobError) -> () + RefUnwindSafe + Send + Sync + 'static);
-    pub type ModificationCallback = (Fn(BitsJob) -> () + RefUnwindSafe + Send + Sync + 'static);
-
-    #[repr(C)]
     pub struct BackgroundCopyCallback {
-        pub interface: IBackgroundCopyCallback,
         // TODO return from callback should be an error that can be logged?
-        pub transferred: Option<Box<TransferredCallback>>,
-        pub error: Option<Box<ErrorCallback>>,
-        pub modification: Option<Box<ModificationCallback>>,
+        pub transferred: Option<Box<TransferredCallback<BitsJob>>>,
+        pub error: Option<Box<ErrorCallback<BitsJob>>>,
+        pub modification: Option<Box<ModificationCallback<BitsJob>>>,
     }
 
-    extern "system" fn query_interface(
-        this: *mut IUnknown,
-        riid: REFIID,
-        obj: *mut *mut c_void,
-    ) -> HRESULT {
-        unsafe {
-            if Guid(*riid) == Guid(IUnknown::uuidof())
-                || Guid(*riid) == Guid(IBackgroundCopyCallback::uuidof())
-            {
-                addref(this);
-                *obj = this as *mut c_void;
-                NOERROR
-            } else {
-                E_NOINTERFACE
+    com_class! {
+        impl BackgroundCopyCallback {
+            interfaces: [IBackgroundCopyCallback],
+            vtbl: IBackgroundCopyCallbackVtbl for IBackgroundCopyCallback {
+                parent: IUnknownVtbl,
+                JobTransferred => job_transferred(job: *mut IBackgroundCopyJob),
+                JobError => job_error(
+                    job: *mut IBackgroundCopyJob,
+                    error: *mut IBackgroundCopyError,
+                ),
+                JobModification => job_modification(job: *mut IBackgroundCopyJob, reserved: DWORD),
             }
         }
     }
 
-    extern "system" fn addref(_this: *mut IUnknown) -> ULONG {
-        // TODO learn Rust synchronization
-        1
-    }
-
-    extern "system" fn release(_this: *mut IUnknown) -> ULONG {
-        // TODO
-        1
-    }
-
-    extern "system" fn transferred_stub(
-        this: *mut IBackgroundCopyCallback,
-        job: *mut IBackgroundCopyJob,
-    ) -> HRESULT {
-        unsafe {
-            let this = this as *mut BackgroundCopyCallback;
-            if let Some(ref cb) = (*this).transferred {
+    impl BackgroundCopyCallback {
+        unsafe fn job_transferred(&self, job: *mut IBackgroundCopyJob) -> HRESULT {
+            if let Some(ref cb) = self.transferred {
                 // TODO: argue about this, BitsJob should probably have an unsafe from_raw that
                 // does this and also ComPtr::from_raw internally
                 (*job).AddRef();
                 let result = catch_unwind(|| cb(BitsJob::from_ptr(ComPtr::from_raw(job))));
-                // TODO: proper logging
                 if let Err(e) = result {
-                    use std::io::Write;
-                    if let Ok(mut file) = std::fs::File::create("C:\\ProgramData\\callbackfail.log")
-                    {
-                        #[allow(unused_must_use)]
-                        {
-                            file.write(format!("{:?}", e.downcast_ref::<String>()).as_bytes());
-                        }
-                    }
+                    log_callback_panic("transferred", &*e);
                 }
             }
+            S_OK
         }
-        S_OK
-    }
 
-    extern "system" fn error_stub(
-        this: *mut IBackgroundCopyCallback,
-        job: *mut IBackgroundCopyJob,
-        error: *mut IBackgroundCopyError,
-    ) -> HRESULT {
-        unsafe {
-            let this = this as *mut BackgroundCopyCallback;
-            if let Some(ref cb) = (*this).error {
-                (*job).AddRef();
+        unsafe fn job_error(
+            &self,
+            job: *mut IBackgroundCopyJob,
+            error: *mut IBackgroundCopyError,
+        ) -> HRESULT {
+            if let Some(ref cb) = self.error {
                 (*error).AddRef();
-                if let Err(_e) = catch_unwind(|| {
-                    cb(
-                        BitsJob::from_ptr(ComPtr::from_raw(job)),
-                        BitsJob::get_error(ComPtr::from_raw(error)).expect("unwrapping"),
-                    )
-                }) {
-                    // TODO logging
+                let error = match BitsJob::get_error(ComPtr::from_raw(error)) {
+                    Ok(error) => error,
+                    Err(e) => {
+                        log_error!(
+                            LogContext::default(),
+                            "error callback couldn't get the error: {}",
+                            e
+                        );
+                        return S_OK;
+                    }
+                };
+                (*job).AddRef();
+                let result = catch_unwind(|| cb(BitsJob::from_ptr(ComPtr::from_raw(job)), error));
+                if let Err(e) = result {
+                    log_callback_panic("error", &*e);
                 }
             }
+            S_OK
         }
-        S_OK
-    }
 
-    extern "system" fn modification_stub(
-        this: *mut IBackgroundCopyCallback,
-        job: *mut IBackgroundCopyJob,
-        _reserved: DWORD,
-    ) -> HRESULT {
-        unsafe {
-            let this = this as *mut BackgroundCopyCallback;
-            if let Some(ref cb) = (*this).modification {
+        unsafe fn job_modification(
+            &self,
+            job: *mut IBackgroundCopyJob,
+            _reserved: DWORD,
+        ) -> HRESULT {
+            if let Some(ref cb) = self.modification {
                 (*job).AddRef();
-                if let Err(_e) = catch_unwind(|| cb(BitsJob::from_ptr(ComPtr::from_raw(job)))) {
-                    // TODO logging
+                if let Err(e) = catch_unwind(|| cb(BitsJob::from_ptr(ComPtr::from_raw(job)))) {
+                    log_callback_panic("modification", &*e);
                 }
             }
+            S_OK
         }
-        S_OK
-    }
-
-    pub static VTBL: IBackgroundCopyCallbackVtbl = IBackgroundCopyCallbackVtbl {
-        parent: IUnknownVtbl {
-            QueryInterface: query_interface,
-            AddRef: addref,
-            Release: release,
-        },
-        JobTransferred: transferred_stub,
-        JobError: error_stub,
-        JobModification: modification_stub,
-    };
+    }
+
+    // The job may be what failed, so there is no GUID to go with this.
+    fn log_callback_panic(callback: &str, payload: &(Any + Send)) {
+        log_error!(
+            LogContext::default(),
+            "{} callback panicked: {}",
+            callback,
+            logging::panic_message(payload)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        for state in 0..BG_JOB_STATE_CANCELLED + 2 {
+            assert_eq!(BG_JOB_STATE::from(JobState::from(state)), state);
+        }
+        assert_eq!(
+            JobState::from(BG_JOB_STATE_CANCELLED + 1),
+            JobState::Other(9)
+        );
+
+        for context in 0..BG_ERROR_CONTEXT_REMOTE_APPLICATION + 2 {
+            assert_eq!(BG_ERROR_CONTEXT::from(ErrorContext::from(context)), context);
+        }
+    }
 }
@@ -1,143 +1,780 @@
 use std::ffi::{OsStr, OsString};
-use std::mem::uninitialized;
+use std::mem;
 use std::result;
+#[cfg(any(windows, test))]
+use std::time::{Duration, Instant};
 
 use bincode::{deserialize, serialize};
 
-use comical::error::{Error, Result};
+use comical::error::Error as ComicalError;
 use comical::guid::Guid;
-use winapi::um::bits::{
-    BG_JOB_STATE_CONNECTING, BG_JOB_STATE_TRANSFERRING, BG_JOB_STATE_TRANSIENT_ERROR,
-};
 
-use pipe::{DuplexPipeConnection, DuplexPipeServer, InboundPipeServer};
+use error::{Error, Result};
+use framing::Framed;
+use logging::LogContext;
 use protocol::*;
-use task_service::run_on_demand;
+#[cfg(windows)]
+use task_service::{run_on_demand, runs_as_task_account, RunningTask};
+#[cfg(windows)]
+use transport::PeerProcess;
+use transport::{Listener, MessageRead, MessageWrite, Transact, Transport};
 
 // The IPC is structured so that the client runs as a named pipe server, accepting connections
 // from the BITS task server once it starts up, which it then uses to issue commands.
 // This is done so that the client can create the pipe and wait for a connection to know when the
 // task is ready for commands; otherwise it would have to repeatedly try to connect until the
 // server creates the pipe.
+//
+// If a task server is already resident, the client asks it to connect instead of starting a new
+// one. A task started with a nonzero `idle_timeout_ms` stays resident until it has been idle that
+// long.
+//
+// Either way the server may never connect, so the client only waits up to a timeout, and gives up
+// early if a task it started has already exited.
+//
+// The pipe names are random, but another process running as the same account could still find
+// and connect to the control pipe. So the client passes a new token to the server, which it must
+// present before anything else, and where the transport can say which process connected, that
+// must be the task we started or the resident server we asked.
 
-pub fn run<F, T>(task_name: &OsStr, f: F) -> Result<T>
+/// How often to check that a task we started is still running, while waiting for it to connect.
+#[cfg(any(windows, test))]
+const LAUNCH_POLL_MS: u64 = 100;
+
+/// How often a monitor reports when nothing has changed, and the fastest it reports changes,
+/// see `MonitorConfig`.
+const MONITOR_INTERVAL_MS: u32 = 10000;
+const MONITOR_MIN_INTERVAL_MS: u32 = 500;
+
+/// A connection to the task server, for issuing commands.
+pub struct BitsClient<T>
+where
+    T: Transport,
+{
+    transport: T,
+    connection: Framed<T::DuplexConnection>,
+    negotiated: Negotiated,
+}
+
+impl<T> BitsClient<T>
+where
+    T: Transport,
+{
+    /// Connect to the task server, starting the task if no server is resident. Fails with
+    /// `Error::TaskDidNotConnect` if the server hasn't connected within `connect_timeout`.
+    #[cfg(windows)]
+    pub fn connect(
+        transport: T,
+        task_name: &OsStr,
+        idle_timeout_ms: u64,
+        connect_timeout: Duration,
+    ) -> Result<Self> {
+        let mut cmd_pipe = transport.duplex_listener()?;
+        let mut token = new_token();
+
+        let resident = match request_resident(&transport, task_name, cmd_pipe.name(), &token) {
+            Some(server) => {
+                log_debug!(
+                    LogContext::default(),
+                    "asked the resident server to connect"
+                );
+                // It may have been shutting down and dropped the request, then it's gone.
+                if cmd_pipe.wait_timeout(Duration::from_millis(LATE_REQUEST_TIMEOUT_MS))? {
+                    Some(server)
+                } else {
+                    log_warn!(
+                        LogContext::default(),
+                        "the resident server didn't connect, starting the task"
+                    );
+                    // A new pipe and token, so the resident server can't connect late.
+                    cmd_pipe = transport.duplex_listener()?;
+                    token = new_token();
+                    None
+                }
+            }
+            None => None,
+        };
+
+        let server = match resident {
+            Some(server) => server,
+            None => {
+                // Start the task, which will connect back to the pipe for commands.
+                let idle_timeout_ms = OsString::from(idle_timeout_ms.to_string());
+                let args: &[&OsStr] = &[
+                    OsStr::new("command-connect"),
+                    cmd_pipe.name(),
+                    &idle_timeout_ms,
+                    OsStr::new(&token),
+                ];
+                log_debug!(LogContext::default(), "starting the task");
+                Server::Started(run_on_demand(task_name, args)?)
+            }
+        };
+
+        if let Err(e) = wait_for_server(&mut cmd_pipe, connect_timeout, || server.is_running()) {
+            log_error!(LogContext::default(), "{}", e);
+            return Err(e);
+        }
+
+        let connection = cmd_pipe.accept()?;
+        check_peer(&connection, server.pid()?)?;
+        BitsClient::with_connection(transport, connection, &token)
+    }
+
+    /// Use a connection from a server that was given `token`, and hasn't sent it yet.
+    pub fn with_connection(
+        transport: T,
+        mut connection: T::DuplexConnection,
+        token: &str,
+    ) -> Result<Self> {
+        check_token(&mut connection, token)?;
+        let negotiated = handshake(&mut connection)?;
+        Ok(BitsClient {
+            transport,
+            connection: Framed::new(connection, MAX_MESSAGE),
+            negotiated,
+        })
+    }
+
+    pub fn negotiated(&self) -> &Negotiated {
+        &self.negotiated
+    }
+
+    /// Start a job, returns its GUID and a monitor that reports until the job finishes.
+    pub fn start_job(
+        &mut self,
+        files: Vec<FileSpec>,
+        properties: JobProperties,
+    ) -> Result<(Guid, JobMonitor<T::InboundListener>)> {
+        let monitor_pipe = self.transport.inbound_listener()?;
+        let command = StartJobCommand {
+            files,
+            properties,
+            monitor: Some(monitor_config(&monitor_pipe)),
+        };
+
+        let mut out_buf = Vec::new();
+        let success =
+            run_command(&mut self.connection, command, &mut out_buf)?.map_err(Error::Command)?;
+        Ok((success.guid, JobMonitor::new(monitor_pipe)))
+    }
+
+    /// Monitor an existing job.
+    pub fn monitor_job(&mut self, guid: Guid) -> Result<JobMonitor<T::InboundListener>> {
+        let monitor_pipe = self.transport.inbound_listener()?;
+        let command = MonitorJobCommand {
+            guid,
+            monitor: Some(monitor_config(&monitor_pipe)),
+        };
+
+        let mut out_buf = Vec::new();
+        run_command(&mut self.connection, command, &mut out_buf)?.map_err(Error::Command)?;
+        Ok(JobMonitor::new(monitor_pipe))
+    }
+
+    pub fn cancel_job(&mut self, guid: Guid) -> Result<()> {
+        let command = CancelJobCommand { guid };
+        let mut out_buf = Vec::new();
+        run_command(&mut self.connection, command, &mut out_buf)?.map_err(Error::Command)?;
+        Ok(())
+    }
+
+    pub fn set_job_priority(&mut self, guid: Guid, priority: JobPriority) -> Result<()> {
+        self.require(CAPABILITY_SET_JOB_PRIORITY, "setting priority")?;
+
+        let command = SetJobPriorityCommand { guid, priority };
+        let mut out_buf = Vec::new();
+        run_command(&mut self.connection, command, &mut out_buf)?.map_err(Error::Command)?;
+        Ok(())
+    }
+
+    pub fn set_job_proxy(&mut self, guid: Guid, proxy: ProxySettings) -> Result<()> {
+        self.require(CAPABILITY_SET_PROXY, "setting proxy")?;
+
+        let command = SetProxyCommand { guid, proxy };
+        let mut out_buf = Vec::new();
+        run_command(&mut self.connection, command, &mut out_buf)?.map_err(Error::Command)?;
+        Ok(())
+    }
+
+    pub fn suspend_job(&mut self, guid: Guid) -> Result<()> {
+        self.require(CAPABILITY_SUSPEND_RESUME, "suspending jobs")?;
+
+        let command = SuspendJobCommand { guid };
+        let mut out_buf = Vec::new();
+        run_command(&mut self.connection, command, &mut out_buf)?.map_err(Error::Command)?;
+        Ok(())
+    }
+
+    pub fn resume_job(&mut self, guid: Guid) -> Result<()> {
+        self.require(CAPABILITY_SUSPEND_RESUME, "resuming jobs")?;
+
+        let command = ResumeJobCommand { guid };
+        let mut out_buf = Vec::new();
+        run_command(&mut self.connection, command, &mut out_buf)?.map_err(Error::Command)?;
+        Ok(())
+    }
+
+    /// Stop the monitor of job `guid` that sends to `pipe_name`, see `JobMonitor::pipe_name`.
+    pub fn stop_monitor(&mut self, guid: Guid, pipe_name: OsString) -> Result<()> {
+        self.require(CAPABILITY_STOP_MONITOR, "stopping monitors")?;
+
+        let command = StopMonitorCommand { guid, pipe_name };
+        let mut out_buf = Vec::new();
+        run_command(&mut self.connection, command, &mut out_buf)?.map_err(Error::Command)?;
+        Ok(())
+    }
+
+    /// Have the server send its log records about this connection, at `level` and above, until
+    /// the connection closes.
+    pub fn forward_logs(&mut self, level: LogLevel) -> Result<LogStream<T::InboundListener>> {
+        self.require(CAPABILITY_FORWARD_LOGS, "forwarding logs")?;
+
+        let log_pipe = self.transport.inbound_listener()?;
+        let command = ForwardLogsCommand {
+            pipe_name: log_pipe.name().to_os_string(),
+            level,
+        };
+        let mut out_buf = Vec::new();
+        run_command(&mut self.connection, command, &mut out_buf)?.map_err(Error::Command)?;
+        Ok(LogStream {
+            pipe: InboundPipe::Listening(log_pipe),
+            buf: Vec::new(),
+        })
+    }
+
+    /// List the jobs with names starting with `name_prefix`, in any of `states` (or any state if
+    /// empty).
+    pub fn list_jobs(
+        &mut self,
+        name_prefix: Option<OsString>,
+        states: Vec<JobState>,
+    ) -> Result<Vec<JobInfo>> {
+        self.require(CAPABILITY_LIST_JOBS, "listing jobs")?;
+
+        let command = ListJobsCommand {
+            name_prefix,
+            states,
+        };
+        let mut out_buf = Vec::new();
+        let success =
+            run_command(&mut self.connection, command, &mut out_buf)?.map_err(Error::Command)?;
+        Ok(success.jobs)
+    }
+
+    fn require(&self, capability: Capabilities, what: &str) -> Result<()> {
+        if self.negotiated.capabilities & capability == 0 {
+            return Err(Error::Message(format!("server doesn't support {}", what)));
+        }
+        Ok(())
+    }
+}
+
+fn monitor_config<L>(monitor_pipe: &L) -> MonitorConfig
+where
+    L: Listener,
+{
+    MonitorConfig {
+        pipe_name: monitor_pipe.name().to_os_string(),
+        interval_ms: MONITOR_INTERVAL_MS,
+        min_interval_ms: MONITOR_MIN_INTERVAL_MS,
+    }
+}
+
+enum InboundPipe<L>
+where
+    L: Listener,
+{
+    Listening(L),
+    Connected(Framed<L::Connection>),
+    Done,
+}
+
+/// Status updates for a job, as they arrive. Iteration ends after the final message (see
+/// `MonitorMessage::is_final`) or the first error.
+pub struct JobMonitor<L>
+where
+    L: Listener,
+{
+    pipe_name: OsString,
+    pipe: InboundPipe<L>,
+    buf: Vec<u8>,
+}
+
+impl<L> JobMonitor<L>
+where
+    L: Listener,
+    L::Connection: MessageRead,
+{
+    fn new(listener: L) -> Self {
+        JobMonitor {
+            pipe_name: listener.name().to_os_string(),
+            pipe: InboundPipe::Listening(listener),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Identifies this monitor to `BitsClient::stop_monitor`.
+    pub fn pipe_name(&self) -> &OsStr {
+        &self.pipe_name
+    }
+
+    fn read_message(&mut self) -> Result<MonitorMessage> {
+        let mut monitor = match mem::replace(&mut self.pipe, InboundPipe::Done) {
+            InboundPipe::Listening(listener) => Framed::new(listener.accept()?, MAX_MESSAGE),
+            InboundPipe::Connected(monitor) => monitor,
+            InboundPipe::Done => return Err(Error::Message("monitor already ended".to_string())),
+        };
+
+        match monitor.read_all(&mut self.buf) {
+            Ok(()) => {}
+            Err(ComicalError::Disconnected) => {
+                return Err(Error::Message(
+                    "monitor pipe closed before the job finished".to_string(),
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let message: MonitorMessage = match deserialize(&self.buf) {
+            Err(e) => return Err(Error::Message(format!("deserialize failed: {}", e))),
+            Ok(message) => message,
+        };
+
+        if !message.is_final() {
+            self.pipe = InboundPipe::Connected(monitor);
+        }
+        Ok(message)
+    }
+}
+
+impl<L> Iterator for JobMonitor<L>
+where
+    L: Listener,
+    L::Connection: MessageRead,
+{
+    type Item = Result<MonitorMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let InboundPipe::Done = self.pipe {
+            return None;
+        }
+        Some(self.read_message())
+    }
+}
+
+/// Log records forwarded from the server, as they arrive, see `BitsClient::forward_logs`.
+/// Iteration ends when the connection closes, or at the first error.
+pub struct LogStream<L>
+where
+    L: Listener,
+{
+    pipe: InboundPipe<L>,
+    buf: Vec<u8>,
+}
+
+impl<L> LogStream<L>
+where
+    L: Listener,
+    L::Connection: MessageRead,
+{
+    fn read_record(&mut self) -> Option<Result<LogRecord>> {
+        let mut pipe = match mem::replace(&mut self.pipe, InboundPipe::Done) {
+            InboundPipe::Listening(listener) => match listener.accept() {
+                Ok(connection) => Framed::new(connection, MAX_MESSAGE),
+                Err(e) => return Some(Err(e.into())),
+            },
+            InboundPipe::Connected(pipe) => pipe,
+            InboundPipe::Done => return None,
+        };
+
+        match pipe.read_all(&mut self.buf) {
+            Ok(()) => {}
+            Err(ComicalError::Disconnected) => return None,
+            Err(e) => return Some(Err(e.into())),
+        }
+        self.pipe = InboundPipe::Connected(pipe);
+        Some(
+            deserialize(&self.buf)
+                .map_err(|e| Error::Message(format!("deserialize failed: {}", e))),
+        )
+    }
+}
+
+impl<L> Iterator for LogStream<L>
+where
+    L: Listener,
+    L::Connection: MessageRead,
+{
+    type Item = Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record()
+    }
+}
+
+/// Wait up to `timeout` for the server to open `listener`. `is_running` is checked periodically,
+/// to give up early if the server has exited.
+#[cfg(any(windows, test))]
+fn wait_for_server<L, F>(listener: &mut L, timeout: Duration, mut is_running: F) -> Result<()>
+where
+    L: Listener,
+    F: FnMut() -> Result<bool>,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        let wait = if now < deadline {
+            (deadline - now).min(Duration::from_millis(LAUNCH_POLL_MS))
+        } else {
+            Duration::from_millis(0)
+        };
+        if listener.wait_timeout(wait)? {
+            return Ok(());
+        }
+
+        if !is_running()? {
+            // It may have connected just before exiting.
+            if listener.wait_timeout(Duration::from_millis(0))? {
+                return Ok(());
+            }
+            return Err(Error::TaskDidNotConnect { exited: true });
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::TaskDidNotConnect { exited: false });
+        }
+    }
+}
+
+/// The server that should connect to the control pipe.
+#[cfg(windows)]
+enum Server {
+    /// Already running, `pid` if the transport could tell.
+    Resident {
+        pid: Option<u32>,
+    },
+    Started(RunningTask),
+}
+
+#[cfg(windows)]
+impl Server {
+    fn is_running(&self) -> Result<bool> {
+        match *self {
+            Server::Resident { .. } => Ok(true),
+            Server::Started(ref task) => Ok(task.is_running()?),
+        }
+    }
+
+    fn pid(&self) -> Result<Option<u32>> {
+        match *self {
+            Server::Resident { pid } => Ok(pid),
+            Server::Started(ref task) => Ok(task.pid()?),
+        }
+    }
+}
+
+/// Ask a resident server to connect to `pipe_name`, returns `None` if there isn't one.
+#[cfg(windows)]
+fn request_resident<T>(
+    transport: &T,
+    task_name: &OsStr,
+    pipe_name: &OsStr,
+    token: &str,
+) -> Option<Server>
 where
-    F: FnOnce(&mut DuplexPipeConnection) -> Result<T>,
+    T: Transport,
 {
-    let mut cmd_pipe = DuplexPipeServer::new()?;
+    let request = ConnectRequest {
+        pipe_name: pipe_name.to_os_string(),
+        token: token.to_string(),
+    };
 
-    {
-        // Start the task, which will connect back to the pipe for commands.
-        let args = &[&OsString::from("command-connect"), cmd_pipe.name()];
-        run_on_demand(task_name, args)?;
-        // TODO: some kind of check that the task is running?
+    let mut pipe = transport
+        .open_outbound(&resident_pipe_name(task_name))
+        .ok()?;
+    let pid = pipe.peer_pid().ok()?;
+
+    // Anyone can create the pipe first, only send the token to the task.
+    let verified = match pid {
+        Some(pid) => runs_as_task_account(pid),
+        None => Ok(false),
+    };
+    match verified {
+        Ok(true) => {}
+        Ok(false) => {
+            log_error!(
+                LogContext::default(),
+                "rejected resident server: process {:?} isn't the task",
+                pid
+            );
+            return None;
+        }
+        Err(e) => {
+            log_warn!(
+                LogContext::default(),
+                "couldn't check the resident server: {}",
+                e
+            );
+            return None;
+        }
     }
 
-    // Do stuff with the connection
-    // TODO: this blocks, fix
-    // TODO: check pid?
-    let mut connection = cmd_pipe.connect()?;
-    f(&mut connection)
+    pipe.write(&mut serialize(&request).unwrap()).ok()?;
+    Some(Server::Resident { pid })
+}
+
+#[cfg(any(windows, test))]
+fn new_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
 }
 
-pub fn run_command<'b, 'c, T>(
-    connection: &mut DuplexPipeConnection,
+/// Check that the process on the other end of `connection` is `expected`, if both are known.
+#[cfg(windows)]
+fn check_peer<C>(connection: &C, expected: Option<u32>) -> Result<()>
+where
+    C: PeerProcess,
+{
+    match (connection.peer_pid()?, expected) {
+        (Some(actual), Some(expected)) if actual != expected => {
+            log_error!(
+                LogContext::default(),
+                "rejected server: process {}, expected {}",
+                actual,
+                expected
+            );
+            Err(Error::UntrustedServer(format!(
+                "connected from process {}, expected {}",
+                actual, expected
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Receive the token, which must be the first message from the server.
+fn check_token<C>(connection: &mut C, token: &str) -> Result<()>
+where
+    C: MessageRead,
+{
+    let mut buf = [0u8; TOKEN_LEN];
+    if connection.read(&mut buf)? != token.as_bytes() {
+        log_error!(LogContext::default(), "rejected server: wrong token");
+        return Err(Error::UntrustedServer("wrong token".to_string()));
+    }
+    Ok(())
+}
+
+/// Receive the server's `Hello` and reply with ours.
+fn handshake<C>(connection: &mut C) -> Result<Negotiated>
+where
+    C: MessageRead + MessageWrite,
+{
+    let mut buf = Vec::new();
+    connection.read_all(&mut buf)?;
+    let server_hello: Hello = match deserialize(&buf) {
+        Err(e) => return Err(Error::Message(format!("deserialize hello failed: {}", e))),
+        Ok(hello) => hello,
+    };
+
+    // Reply even if the versions are incompatible, so the server can also report it.
+    let hello = Hello::new();
+    connection.write(&mut serialize(&hello).unwrap())?;
+
+    Ok(negotiate(&hello, &server_hello)?)
+}
+
+pub fn run_command<'b, 'c, C, T>(
+    connection: &mut C,
     cmd: T,
-    out_buf: &'c mut [u8],
+    out_buf: &'c mut Vec<u8>,
 ) -> Result<result::Result<T::Success, T::Failure>>
 where
+    C: Transact,
     T: CommandType<'b, 'c, 'c>,
 {
     // Serialize should never fail.
     let mut cmd_buf = serialize(&T::new(cmd)).unwrap();
-    assert!(cmd_buf.len() <= MAX_COMMAND);
 
-    let out_buf = connection.transact(&mut cmd_buf, out_buf)?;
+    connection.transact(&mut cmd_buf, out_buf)?;
 
+    let out_buf: &'c Vec<u8> = out_buf;
     match deserialize(out_buf) {
         Err(e) => Err(Error::Message(format!("deserialize failed: {}", e))),
         Ok(r) => Ok(r),
     }
 }
 
-fn monitor_loop(mut monitor_pipe: InboundPipeServer) -> Result<()> {
-    let mut monitor = monitor_pipe.connect()?;
-    println!("connected to monitor pipe");
-    loop {
-        let mut out_buf: [u8; 512] = unsafe { uninitialized() };
-        let status: BitsJobStatus = deserialize(monitor.read(&mut out_buf)?).unwrap();
-        println!("{:?}", status);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::thread;
+
+    use backend::{DownloadBackend, DownloadJob};
+    use server::run_commands;
+    use sim::SimBackend;
+    use temp_dir::TempDir;
+    use transport::MemoryTransport;
+
+    const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn start_and_monitor() {
+        let root = TempDir::new("client");
+        root.create_file("update.mar", b"0123456789");
 
-        if !(status.state == BG_JOB_STATE_CONNECTING
-            || status.state == BG_JOB_STATE_TRANSFERRING
-            || status.state == BG_JOB_STATE_TRANSIENT_ERROR)
-        {
-            break;
+        let backend = SimBackend::new(root.to_path_buf(), None);
+        let transport = MemoryTransport::new();
+        let listener = transport.duplex_listener().unwrap();
+        let server = {
+            let (backend, transport) = (backend.clone(), transport.clone());
+            let name = listener.name().to_os_string();
+            thread::spawn(move || run_commands(&backend, &transport, &name, TOKEN))
+        };
+
+        let mut client =
+            BitsClient::with_connection(transport, listener.accept().unwrap(), TOKEN).unwrap();
+        let files = vec![FileSpec {
+            url: OsString::from("http://localhost/update.mar"),
+            save_path: root.join("saved.mar").into_os_string(),
+        }];
+        let properties = JobProperties {
+            display_name: OsString::from("test"),
+            description: OsString::new(),
+            priority: JobPriority::Normal,
+            proxy: ProxySettings::Preconfig,
+        };
+        let (guid, monitor) = client.start_job(files, properties).unwrap();
+        assert_eq!(client.list_jobs(None, Vec::new()).unwrap()[0].guid, guid);
+
+        assert!(backend.step(&guid).unwrap());
+        let messages = monitor.collect::<Result<Vec<_>>>().unwrap();
+        match messages.last() {
+            Some(MonitorMessage::Completed(Ok(result))) => assert!(!result.is_partial()),
+            m => panic!("unexpected {:?}", m),
+        }
+        assert!(client.list_jobs(None, Vec::new()).unwrap().is_empty());
+        match client.cancel_job(guid) {
+            Err(Error::Command(_)) => {}
+            r => panic!("unexpected {:?}", r),
         }
+
+        drop(client);
+        assert_eq!(server.join().unwrap(), Ok(()));
     }
-    Ok(())
-}
 
-pub fn bits_start(
-    connection: &mut DuplexPipeConnection,
-    url: OsString,
-    save_path: OsString,
-) -> Result<()> {
-    let monitor_pipe = InboundPipeServer::new()?;
-
-    let command = StartJobCommand {
-        url,
-        save_path,
-        monitor: Some(MonitorConfig {
-            pipe_name: monitor_pipe.name().to_os_string(),
-            interval_ms: 10000,
-        }),
-    };
+    #[test]
+    fn monitor_transferred() {
+        let root = TempDir::new("client");
+        root.create_file("update.mar", b"0123456789");
+
+        let backend = SimBackend::new(root.to_path_buf(), None);
+        let transport = MemoryTransport::new();
+        let listener = transport.duplex_listener().unwrap();
+        let server = {
+            let (backend, transport) = (backend.clone(), transport.clone());
+            let name = listener.name().to_os_string();
+            thread::spawn(move || run_commands(&backend, &transport, &name, TOKEN))
+        };
 
-    let mut out_buf: [u8; MAX_RESPONSE] = unsafe { uninitialized() };
-    let result = run_command(connection, command, &mut out_buf)?;
+        // Transferred before anyone was monitoring, so no callback will say so.
+        let save_path = root.join("saved.mar");
+        let mut job = backend.create_job(OsStr::new("test")).unwrap();
+        let guid = job.guid().unwrap();
+        job.add_file(OsStr::new("update.mar"), save_path.as_os_str())
+            .unwrap();
+        job.resume().unwrap();
+        assert!(backend.step(&guid).unwrap());
+        assert_eq!(job.get_status().unwrap().state, JobState::Transferred);
 
-    match result {
-        Ok(r) => {
-            println!("start success, guid = {}", r.guid);
-            monitor_loop(monitor_pipe)?;
-            Ok(())
+        let mut client =
+            BitsClient::with_connection(transport, listener.accept().unwrap(), TOKEN).unwrap();
+        let monitor = client.monitor_job(guid).unwrap();
+        let messages = monitor.collect::<Result<Vec<_>>>().unwrap();
+        match messages.last() {
+            Some(MonitorMessage::Completed(Ok(result))) => assert!(!result.is_partial()),
+            m => panic!("unexpected {:?}", m),
         }
-        Err(e) => Err(Error::Message(format!("error from server {:?}", e))),
+        assert_eq!(fs::read_to_string(&save_path).unwrap(), "0123456789");
+
+        drop(client);
+        assert_eq!(server.join().unwrap(), Ok(()));
     }
-}
 
-pub fn bits_monitor(connection: &mut DuplexPipeConnection, guid: Guid) -> Result<()> {
-    let monitor_pipe = InboundPipeServer::new()?;
+    #[test]
+    fn wrong_token() {
+        let transport = MemoryTransport::new();
+        let listener = transport.duplex_listener().unwrap();
+        let server = {
+            let (backend, transport) = (SimBackend::new(env::temp_dir(), None), transport.clone());
+            let name = listener.name().to_os_string();
+            thread::spawn(move || run_commands(&backend, &transport, &name, &new_token()))
+        };
 
-    let command = MonitorJobCommand {
-        guid,
-        monitor: Some(MonitorConfig {
-            pipe_name: monitor_pipe.name().to_os_string(),
-            interval_ms: 10000,
-        }),
-    };
+        match BitsClient::with_connection(transport, listener.accept().unwrap(), TOKEN) {
+            Err(Error::UntrustedServer(_)) => {}
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+        // The server sees the client hang up before the handshake.
+        assert!(server.join().unwrap().is_err());
+    }
 
-    let mut out_buf: [u8; MAX_RESPONSE] = unsafe { uninitialized() };
-    let result = run_command(connection, command, &mut out_buf)?;
+    #[test]
+    fn forward_logs() {
+        let transport = MemoryTransport::new();
+        let listener = transport.duplex_listener().unwrap();
+        let server = {
+            let (backend, transport) = (SimBackend::new(env::temp_dir(), None), transport.clone());
+            let name = listener.name().to_os_string();
+            thread::spawn(move || run_commands(&backend, &transport, &name, TOKEN))
+        };
 
-    match result {
-        Ok(_) => {
-            println!("monitor success");
-            monitor_loop(monitor_pipe)?;
-            Ok(())
-        }
-        Err(e) => Err(Error::Message(format!("error from server {:?}", e))),
+        let mut client =
+            BitsClient::with_connection(transport, listener.accept().unwrap(), TOKEN).unwrap();
+        let logs = client.forward_logs(LogLevel::Warn).unwrap();
+        let guid = Guid::new_v4();
+        assert!(client.cancel_job(guid.clone()).is_err());
+        drop(client);
+        assert_eq!(server.join().unwrap(), Ok(()));
+
+        // Only the failed cancel is bad enough, the stream ends with the connection.
+        let records = logs.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].level, LogLevel::Warn);
+        assert_eq!(records[0].context.request, Some(2));
+        assert_eq!(records[0].context.job, Some(guid));
     }
-}
 
-pub fn bits_cancel(connection: &mut DuplexPipeConnection, guid: Guid) -> Result<()> {
-    let command = CancelJobCommand { guid };
-    let mut out_buf: [u8; MAX_RESPONSE] = unsafe { uninitialized() };
-    let result = run_command(connection, command, &mut out_buf)?;
-    println!("Debug result: {:?}", result);
-    match result {
-        Ok(_) => Ok(()),
-        Err(e) => Err(Error::Message(format!("error from server {:?}", e))),
+    #[test]
+    fn server_did_not_connect() {
+        let transport = MemoryTransport::new();
+        let mut listener = transport.duplex_listener().unwrap();
+
+        // Still running, but never connects.
+        let start = Instant::now();
+        match wait_for_server(&mut listener, Duration::from_millis(50), || Ok(true)) {
+            Err(Error::TaskDidNotConnect { exited: false }) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // Exited, no need to wait out the timeout.
+        let start = Instant::now();
+        match wait_for_server(&mut listener, Duration::from_secs(60), || Ok(false)) {
+            Err(Error::TaskDidNotConnect { exited: true }) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // Takes a while, but connects.
+        let name = listener.name().to_os_string();
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(150));
+            transport.open_duplex(&name).unwrap()
+        });
+        wait_for_server(&mut listener, Duration::from_secs(60), || Ok(true)).unwrap();
+        let _server_end = server.join().unwrap();
+        listener.accept().unwrap();
     }
 }
@@ -0,0 +1,99 @@
+use std::fmt;
+use std::result;
+
+use comical;
+use comical::error::ErrorCode;
+
+use protocol::{self, CommandFailure, HResult};
+
+// The codes that get failures of their own. Spelled out rather than taken from the Windows
+// headers, so that the BITS backend and the sim can share the mapping.
+pub const E_ACCESSDENIED: i32 = 0x8007_0005u32 as i32;
+pub const E_INVALIDARG: i32 = 0x8007_0057u32 as i32;
+pub const BG_E_NOT_FOUND: i32 = 0x8020_0001u32 as i32;
+pub const ERROR_ACCESS_DENIED: u32 = 5;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failure of a Windows API or other low level call.
+    Comical(comical::error::Error),
+    IncompatibleVersion(protocol::IncompatibleVersion),
+    /// The server reported that a command failed.
+    Command(CommandFailure),
+    /// The task server never connected to the client, `exited` if it stopped running first.
+    TaskDidNotConnect {
+        exited: bool,
+    },
+    /// Something other than the expected task server connected to the control pipe.
+    UntrustedServer(String),
+    Message(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Comical(e) => write!(f, "{}", e),
+            Error::IncompatibleVersion(e) => write!(f, "{}", e),
+            Error::Command(failure) => write!(f, "error from server: {}", failure),
+            Error::TaskDidNotConnect { exited: true } => {
+                f.write_str("task exited without connecting")
+            }
+            Error::TaskDidNotConnect { exited: false } => {
+                f.write_str("task didn't connect before the timeout")
+            }
+            Error::UntrustedServer(ref reason) => write!(f, "untrusted server: {}", reason),
+            Error::Message(ref msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<comical::error::Error> for Error {
+    fn from(error: comical::error::Error) -> Self {
+        Error::Comical(error)
+    }
+}
+
+impl From<comical::error::Error> for CommandFailure {
+    fn from(error: comical::error::Error) -> Self {
+        use comical::error::Error;
+
+        match error {
+            Error::Api(_, ErrorCode::HResult(BG_E_NOT_FOUND), _) => CommandFailure::JobNotFound,
+            Error::Api(_, ErrorCode::HResult(E_ACCESSDENIED), _)
+            | Error::Api(_, ErrorCode::DWord(ERROR_ACCESS_DENIED), _) => {
+                CommandFailure::AccessDenied
+            }
+            Error::Api(api, ErrorCode::HResult(E_INVALIDARG), _) => {
+                CommandFailure::InvalidArgument(api.to_string())
+            }
+            Error::Api(api, ErrorCode::HResult(hresult), _) => CommandFailure::Bits {
+                context: api.to_string(),
+                hresult: HResult(hresult),
+            },
+            Error::Api(..) | Error::Disconnected | Error::MessageTooLong { .. } => {
+                CommandFailure::Internal(error.to_string())
+            }
+            Error::Message(msg) => CommandFailure::Internal(msg),
+        }
+    }
+}
+
+impl From<protocol::IncompatibleVersion> for Error {
+    fn from(error: protocol::IncompatibleVersion) -> Self {
+        Error::IncompatibleVersion(error)
+    }
+}
+
+impl From<comical::guid::ParseGuidError> for Error {
+    fn from(error: comical::guid::ParseGuidError) -> Self {
+        Error::Message(error.to_string())
+    }
+}
+
+impl From<Error> for String {
+    fn from(error: Error) -> Self {
+        error.to_string()
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
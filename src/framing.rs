@@ -0,0 +1,172 @@
+use comical::error::{Error, Result};
+
+use transport::{MessageRead, MessageWrite, Transact};
+
+// Length-prefixed messages on top of a message transport. Each message is sent as a 4 byte
+// little-endian length, followed by the message itself, so the receiver can refuse a message
+// that is too long before reading it. After a refused message the connection is out of step and
+// should be dropped.
+
+pub struct Framed<C> {
+    connection: C,
+    max_message: usize,
+}
+
+impl<C> Framed<C> {
+    /// Messages longer than `max_message` bytes fail to send or receive with
+    /// `Error::MessageTooLong`.
+    pub fn new(connection: C, max_message: usize) -> Self {
+        Framed {
+            connection,
+            max_message,
+        }
+    }
+
+    fn check_len(&self, len: usize) -> Result<()> {
+        if len > self.max_message {
+            Err(Error::MessageTooLong {
+                len,
+                max: self.max_message,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<C> Framed<C>
+where
+    C: MessageRead,
+{
+    fn read_header(&mut self) -> Result<usize> {
+        let mut header = [0u8; 4];
+        let header = self.connection.read(&mut header)?;
+        if header.len() != 4 {
+            return Err(Error::Message(format!(
+                "message header of {} bytes",
+                header.len()
+            )));
+        }
+
+        let len = (header[0] as usize)
+            | (header[1] as usize) << 8
+            | (header[2] as usize) << 16
+            | (header[3] as usize) << 24;
+        self.check_len(len)?;
+        Ok(len)
+    }
+
+    fn read_body(&mut self, out_buf: &mut [u8]) -> Result<()> {
+        let len = self.connection.read(out_buf)?.len();
+        if len != out_buf.len() {
+            return Err(Error::Message(format!(
+                "message of {} bytes, header said {}",
+                len,
+                out_buf.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<C> MessageRead for Framed<C>
+where
+    C: MessageRead,
+{
+    fn read<'b>(&mut self, out_buf: &'b mut [u8]) -> Result<&'b mut [u8]> {
+        let len = self.read_header()?;
+        if len > out_buf.len() {
+            return Err(Error::Message(format!(
+                "message of {} bytes doesn't fit in {} byte buffer",
+                len,
+                out_buf.len()
+            )));
+        }
+
+        let out_buf = &mut out_buf[..len];
+        self.read_body(out_buf)?;
+        Ok(out_buf)
+    }
+
+    fn read_all(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        let len = self.read_header()?;
+        out.clear();
+        out.resize(len, 0);
+        self.read_body(out)
+    }
+}
+
+impl<C> MessageWrite for Framed<C>
+where
+    C: MessageWrite,
+{
+    fn write(&mut self, in_buf: &mut [u8]) -> Result<()> {
+        let len = in_buf.len();
+        self.check_len(len)?;
+        if len > u32::max_value() as usize {
+            return Err(Error::MessageTooLong {
+                len,
+                max: u32::max_value() as usize,
+            });
+        }
+
+        let mut header = [
+            len as u8,
+            (len >> 8) as u8,
+            (len >> 16) as u8,
+            (len >> 24) as u8,
+        ];
+        self.connection.write(&mut header)?;
+        self.connection.write(in_buf)
+    }
+}
+
+impl<C> Transact for Framed<C>
+where
+    C: MessageRead + MessageWrite,
+{
+    fn transact(&mut self, in_buf: &mut [u8], out: &mut Vec<u8>) -> Result<()> {
+        self.write(in_buf)?;
+        self.read_all(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use transport::MemoryConnection;
+
+    #[test]
+    fn framing() {
+        let (ours, theirs) = MemoryConnection::pair();
+        let (mut ours, mut theirs) = (Framed::new(ours, 8), Framed::new(theirs, 16));
+
+        let mut out = Vec::new();
+        ours.write(&mut b"12345678".to_vec()).unwrap();
+        theirs.read_all(&mut out).unwrap();
+        assert_eq!(out, b"12345678");
+        ours.write(&mut vec![]).unwrap();
+        theirs.read_all(&mut out).unwrap();
+        assert_eq!(out, b"");
+
+        // Too long to send, nothing is sent.
+        match ours.write(&mut b"123456789".to_vec()) {
+            Err(Error::MessageTooLong { len: 9, max: 8 }) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // Too long to receive.
+        theirs.write(&mut b"123456789".to_vec()).unwrap();
+        match ours.read_all(&mut out) {
+            Err(Error::MessageTooLong { len: 9, max: 8 }) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        drop(ours);
+        match theirs.read_all(&mut out) {
+            Err(Error::Disconnected) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,34 @@
+// BITS downloads run from a scheduled task on behalf of clients. `client::BitsClient` is the
+// client side; the `bitstask` binary is a command line front end to it, and is also the task.
+
+extern crate bincode;
+extern crate comical;
+extern crate rand;
+extern crate serde;
+extern crate serde_derive;
+extern crate winapi;
+extern crate wio;
+
+// First, so that its macros are available to the rest.
+#[macro_use]
+pub mod logging;
+
+mod backend;
+#[cfg(windows)]
+mod bits;
+pub mod client;
+pub mod error;
+mod framing;
+mod monitor;
+#[cfg(windows)]
+pub mod pipe;
+pub mod protocol;
+pub mod server;
+mod sim;
+#[cfg(windows)]
+pub mod task_service;
+#[cfg(test)]
+mod temp_dir;
+pub mod transport;
+#[cfg(unix)]
+pub mod unix_socket;
@@ -0,0 +1,484 @@
+// Logging, for both the client and the task server.
+//
+// There is one logger per process. Records at or above its level are appended to `<name>.log` in
+// the log directory; once that reaches its size limit it is renamed to `<name>.1.log` (shifting
+// older files up, the oldest is deleted) and a new one is started.
+//
+// A control connection can also ask for the records about it to be forwarded to the client, see
+// `Command::ForwardLogs`. This is independent of the logger's own level.
+//
+// Nothing here fails: a record that can't be written is dropped.
+
+use std::any::Any;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, MutexGuard, Once};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use comical::guid::Guid;
+
+pub use protocol::{LogContext, LogLevel, LogRecord};
+
+/// The log directory, `%ProgramData%\bitstask` by default.
+pub static LOG_DIR_VAR: &'static str = "BITSTASK_LOG_DIR";
+/// The least severe level to log, `info` by default.
+pub static LOG_LEVEL_VAR: &'static str = "BITSTASK_LOG_LEVEL";
+
+const MAX_FILE_LEN: u64 = 0x10_0000;
+const OLD_FILES: usize = 3;
+
+#[macro_export]
+macro_rules! log_error {
+    ($context:expr, $($arg:tt)+) => {
+        $crate::logging::log($crate::logging::LogLevel::Error, &$context, format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($context:expr, $($arg:tt)+) => {
+        $crate::logging::log($crate::logging::LogLevel::Warn, &$context, format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($context:expr, $($arg:tt)+) => {
+        $crate::logging::log($crate::logging::LogLevel::Info, &$context, format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($context:expr, $($arg:tt)+) => {
+        $crate::logging::log($crate::logging::LogLevel::Debug, &$context, format_args!($($arg)+))
+    };
+}
+
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+    /// Where to write log files, or `None` to not write any.
+    pub dir: Option<PathBuf>,
+    /// Log files are named after this.
+    pub name: String,
+    pub level: LogLevel,
+    /// Start a new file once the current one would grow past this.
+    pub max_file_len: u64,
+    /// How many full files to keep.
+    pub old_files: usize,
+}
+
+impl LogConfig {
+    /// Log to `<name>.log`, with the directory and level from the environment.
+    pub fn from_env(name: &str) -> Result<Self, String> {
+        let dir = match env::var_os(LOG_DIR_VAR) {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => env::var_os("ProgramData").map(|dir| PathBuf::from(dir).join("bitstask")),
+        };
+        let level = match env::var(LOG_LEVEL_VAR) {
+            Ok(level) => level.parse()?,
+            Err(_) => LogLevel::Info,
+        };
+
+        Ok(LogConfig {
+            dir,
+            name: name.to_string(),
+            level,
+            max_file_len: MAX_FILE_LEN,
+            old_files: OLD_FILES,
+        })
+    }
+}
+
+/// Configure the process's logger. Until this is called nothing is written to files, but records
+/// are still forwarded.
+pub fn init(config: LogConfig) {
+    let LogConfig {
+        dir,
+        name,
+        level,
+        max_file_len,
+        old_files,
+    } = config;
+
+    let mut logger = lock();
+    logger.level = level;
+    logger.file = dir.map(|dir| {
+        Arc::new(Mutex::new(LogFile {
+            dir,
+            name,
+            max_len: max_file_len,
+            old_files,
+            file: None,
+            len: 0,
+        }))
+    });
+}
+
+/// Log a message, usually through the `log_*!` macros.
+pub fn log(level: LogLevel, context: &LogContext, args: fmt::Arguments) {
+    let (record, file) = {
+        let mut logger = lock();
+        let write = level <= logger.level && logger.file.is_some();
+        if !write && !logger.forwards.iter().any(|f| f.wants(level, context)) {
+            return;
+        }
+
+        let record = LogRecord {
+            time_ms: now_ms(),
+            level,
+            context: context.clone(),
+            message: args.to_string(),
+        };
+
+        // Forwards end when their receiver goes away.
+        logger
+            .forwards
+            .retain(|f| !f.wants(level, context) || f.tx.send(record.clone()).is_ok());
+
+        let file = if write { logger.file.clone() } else { None };
+        (record, file)
+    };
+
+    // Outside the logger's lock, so that file I/O doesn't hold up other threads' forwarding.
+    if let Some(file) = file {
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        #[allow(unused_must_use)]
+        {
+            file.write(format!("{}\n", record).as_bytes());
+        }
+    }
+}
+
+/// Send records about control connection `connection` at `level` and above to `tx`, until
+/// `stop_forwarding`.
+pub fn forward(connection: u64, level: LogLevel, tx: Sender<LogRecord>) {
+    lock().forwards.push(Forward {
+        connection,
+        level,
+        tx,
+    });
+}
+
+/// Stop all forwarding for `connection`.
+pub fn stop_forwarding(connection: u64) {
+    lock().forwards.retain(|f| f.connection != connection);
+}
+
+/// The message of a panic caught with `catch_unwind`, if it had one.
+pub fn panic_message(payload: &(Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<&'static str>() {
+        message
+    } else {
+        "no message"
+    }
+}
+
+impl LogContext {
+    pub fn job(guid: &Guid) -> Self {
+        LogContext {
+            job: Some(guid.clone()),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_job(&self, guid: &Guid) -> Self {
+        LogContext {
+            job: Some(guid.clone()),
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sep = "";
+        if let Some(connection) = self.connection {
+            write!(f, "conn {}", connection)?;
+            sep = " ";
+        }
+        if let Some(request) = self.request {
+            write!(f, "{}req {}", sep, request)?;
+            sep = " ";
+        }
+        if let Some(ref job) = self.job {
+            write!(f, "{}job {}", sep, job)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        })
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match &*s.to_lowercase() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("unknown log level {}", s)),
+        }
+    }
+}
+
+/// One line, as written to the log file.
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:5} ", format_time(self.time_ms), self.level)?;
+        let context = self.context.to_string();
+        if !context.is_empty() {
+            write!(f, "[{}] ", context)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+struct Logger {
+    level: LogLevel,
+    file: Option<Arc<Mutex<LogFile>>>,
+    forwards: Vec<Forward>,
+}
+
+struct Forward {
+    connection: u64,
+    level: LogLevel,
+    tx: Sender<LogRecord>,
+}
+
+impl Forward {
+    fn wants(&self, level: LogLevel, context: &LogContext) -> bool {
+        level <= self.level && context.connection == Some(self.connection)
+    }
+}
+
+static INIT: Once = Once::new();
+static mut LOGGER: Option<&'static Mutex<Logger>> = None;
+
+fn lock() -> MutexGuard<'static, Logger> {
+    INIT.call_once(|| {
+        let logger = Logger {
+            level: LogLevel::Info,
+            file: None,
+            forwards: Vec::new(),
+        };
+        unsafe { LOGGER = Some(Box::leak(Box::new(Mutex::new(logger)))) };
+    });
+
+    // Carry on after a panic elsewhere, there is nothing in the logger it could have broken.
+    let logger = unsafe { LOGGER }.unwrap();
+    logger.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A log file that is rotated once it gets too long.
+struct LogFile {
+    dir: PathBuf,
+    name: String,
+    max_len: u64,
+    old_files: usize,
+    /// Opened on the first write.
+    file: Option<File>,
+    /// How long the file was when opened, plus what this process has written since. Other
+    /// processes may have written more.
+    len: u64,
+}
+
+impl LogFile {
+    fn path(&self, n: usize) -> PathBuf {
+        if n == 0 {
+            self.dir.join(format!("{}.log", self.name))
+        } else {
+            self.dir.join(format!("{}.{}.log", self.name, n))
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+        if self.len > 0 && self.len + line.len() as u64 > self.max_len {
+            // Another process may have rotated the file already, check the one there now.
+            self.open()?;
+            if self.len > 0 && self.len + line.len() as u64 > self.max_len {
+                self.rotate()?;
+            }
+        }
+
+        self.file.as_mut().unwrap().write_all(line)?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Other processes may be appending to the same file.
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(0))?;
+        self.len = file.metadata()?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // Close before renaming.
+        self.file = None;
+
+        let oldest = self.path(self.old_files);
+        if oldest.exists() {
+            fs::remove_file(oldest)?;
+        }
+        for n in (0..self.old_files).rev() {
+            let path = self.path(n);
+            if path.exists() {
+                fs::rename(path, self.path(n + 1))?;
+            }
+        }
+
+        self.open()
+    }
+}
+
+fn now_ms() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() * 1000 + now.subsec_millis() as u64
+}
+
+/// Format milliseconds since the Unix epoch as an ISO 8601 UTC time.
+fn format_time(time_ms: u64) -> String {
+    let secs = time_ms / 1000;
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Days to a civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        time_ms % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    use temp_dir::TempDir;
+
+    #[test]
+    fn times() {
+        assert_eq!(format_time(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_time(951_782_400_000), "2000-02-29T00:00:00.000Z");
+        assert_eq!(format_time(1_792_236_273_123), "2026-10-17T11:24:33.123Z");
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = TempDir::new("log");
+        let mut file = LogFile {
+            dir: dir.to_path_buf(),
+            name: "test".to_string(),
+            max_len: 10,
+            old_files: 2,
+            file: None,
+            len: 0,
+        };
+
+        for line in &["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            file.write(line.as_bytes()).unwrap();
+        }
+        drop(file);
+
+        let read = |name| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("test.log"), "four\nfive\n");
+        assert_eq!(read("test.1.log"), "three\n");
+        assert_eq!(read("test.2.log"), "one\ntwo\n");
+        assert!(!dir.join("test.3.log").exists());
+    }
+
+    #[test]
+    fn shared_rotation() {
+        let dir = TempDir::new("log");
+        let new_file = || LogFile {
+            dir: dir.to_path_buf(),
+            name: "test".to_string(),
+            max_len: 10,
+            old_files: 2,
+            file: None,
+            len: 0,
+        };
+        // As if in two processes.
+        let (mut a, mut b) = (new_file(), new_file());
+
+        a.write(b"one\n").unwrap();
+        b.write(b"two\n").unwrap();
+        a.write(b"three\n").unwrap();
+        b.write(b"four\n").unwrap();
+        // `a` still counts the file that `b` just rotated.
+        a.write(b"five\n").unwrap();
+        drop((a, b));
+
+        let read = |name| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("test.log"), "four\nfive\n");
+        assert_eq!(read("test.1.log"), "one\ntwo\nthree\n");
+        assert!(!dir.join("test.2.log").exists());
+    }
+
+    #[test]
+    fn forwarding() {
+        let connection = u64::max_value();
+        let context = LogContext {
+            connection: Some(connection),
+            ..Default::default()
+        };
+        let (tx, rx) = channel();
+        forward(connection, LogLevel::Info, tx);
+
+        log_debug!(context, "too verbose");
+        log_info!(LogContext::default(), "not about this connection");
+        log_warn!(context, "forwarded {}", 1);
+        stop_forwarding(connection);
+        log_error!(context, "stopped");
+
+        let records = rx.iter().collect::<Vec<_>>();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].level, LogLevel::Warn);
+        assert_eq!(records[0].message, "forwarded 1");
+    }
+}
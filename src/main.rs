@@ -1,34 +1,51 @@
-extern crate bincode;
+#[cfg_attr(windows, macro_use)]
+extern crate bitstask;
 extern crate comical;
-extern crate rand;
-extern crate serde;
-extern crate serde_derive;
 extern crate winapi;
-extern crate wio;
-
-mod bits;
-mod client;
-mod pipe;
-mod protocol;
-mod server;
-mod task_service;
 
+#[cfg(windows)]
 use std::env;
-use std::ffi::OsString;
-use std::fs::File;
-use std::io::Write;
+#[cfg(windows)]
+use std::ffi::{OsStr, OsString};
+#[cfg(windows)]
+use std::ops::{Deref, DerefMut};
 use std::process;
+#[cfg(windows)]
 use std::ptr::null_mut;
+#[cfg(windows)]
 use std::str::FromStr;
+#[cfg(windows)]
+use std::time::Duration;
 
+#[cfg(windows)]
+use bitstask::client::{BitsClient, JobMonitor, LogStream};
+#[cfg(windows)]
+use bitstask::logging::{self, LogConfig, LogContext, LogLevel};
+#[cfg(windows)]
+use bitstask::pipe::{InboundPipeServer, NamedPipeTransport};
+#[cfg(windows)]
+use bitstask::protocol::{
+    FileSpec, JobPriority, JobProperties, MonitorMessage, MonitorShutdown, ProxySettings,
+};
+#[cfg(windows)]
+use bitstask::transport::{Listener, MessageRead};
+#[cfg(windows)]
+use bitstask::{server, task_service};
+#[cfg(windows)]
 use comical::check_api_hr;
+#[cfg(windows)]
 use comical::com::ComInited;
+#[cfg(windows)]
 use comical::guid::Guid;
+#[cfg(windows)]
 use winapi::shared::rpcdce::{RPC_C_AUTHN_LEVEL_DEFAULT, RPC_C_IMP_LEVEL_IMPERSONATE};
+#[cfg(windows)]
 use winapi::um::combaseapi::CoInitializeSecurity;
 
+#[cfg(windows)]
 fn main() {
     if let Err(err) = entry() {
+        log_error!(LogContext::default(), "{}", err);
         eprintln!("{}", err);
         process::exit(1);
     } else {
@@ -36,9 +53,27 @@ fn main() {
     }
 }
 
+// The library builds anywhere so it can be tested, but the task and client need Windows.
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("bitstask only runs on Windows");
+    process::exit(1);
+}
+
+#[cfg(windows)]
 static TASK_NAME: &'static str = "MozillaBitsTask1234";
+#[cfg(windows)]
 static EXE_NAME: &'static str = "bitstask";
+#[cfg(windows)]
+static IDLE_TIMEOUT_VAR: &'static str = "BITSTASK_IDLE_TIMEOUT_MS";
+#[cfg(windows)]
+static CONNECT_TIMEOUT_VAR: &'static str = "BITSTASK_CONNECT_TIMEOUT_MS";
+#[cfg(windows)]
+static FORWARD_LOGS_VAR: &'static str = "BITSTASK_FORWARD_LOGS";
+#[cfg(windows)]
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 30000;
 
+#[cfg(windows)]
 fn entry() -> Result<(), String> {
     let args: Vec<_> = env::args_os().collect();
 
@@ -46,6 +81,9 @@ fn entry() -> Result<(), String> {
         return Err(format!("Usage: {} <command>", EXE_NAME));
     }
 
+    let log_name = if args[1] == "task" { "task" } else { "client" };
+    logging::init(LogConfig::from_env(log_name)?);
+
     let _ci = ComInited::init_sta()?;
 
     // TODO: there should probably be a comical helper for this
@@ -67,9 +105,25 @@ fn entry() -> Result<(), String> {
 
     let task_name = OsString::from(TASK_NAME);
 
+    // How long a task started by this client should stay resident once idle, 0 to exit as soon
+    // as this client is done.
+    let idle_timeout_ms = env_ms(IDLE_TIMEOUT_VAR, 0)?;
+    // How long to wait for the task to connect.
+    let connect_timeout =
+        Duration::from_millis(env_ms(CONNECT_TIMEOUT_VAR, DEFAULT_CONNECT_TIMEOUT_MS)?);
+    // Print the server's log records at this level and above, once each command is done.
+    let forward_logs = match env::var(FORWARD_LOGS_VAR) {
+        Ok(level) => Some(level.parse::<LogLevel>()?),
+        Err(_) => None,
+    };
+    let connect_client = || connect(&task_name, idle_timeout_ms, connect_timeout, forward_logs);
+
     Ok(match &*args[1].to_string_lossy() {
         "install" => if cmd_args.is_empty() {
-            task_service::install(&task_name, &OsString::from("task $(Arg0) $(Arg1)"))?;
+            task_service::install(
+                &task_name,
+                &OsString::from("task $(Arg0) $(Arg1) $(Arg2) $(Arg3)"),
+            )?;
         } else {
             return Err("install takes no argments".to_string());
         },
@@ -78,45 +132,231 @@ fn entry() -> Result<(), String> {
         } else {
             return Err("uninstall takes no arguments".to_string());
         },
-        "bits-start" => if cmd_args.len() == 1 {
-            client::run(&task_name, |c| {
-                client::bits_start(
-                    c,
-                    cmd_args[0].clone(),
-                    OsString::from("c:\\ProgramData\\update.mar"),
-                )?;
-                Ok(())
-            })?;
+        "bits-start" => if !cmd_args.is_empty() {
+            let files = if cmd_args.len() == 1 {
+                vec![FileSpec {
+                    url: cmd_args[0].clone(),
+                    save_path: OsString::from("c:\\ProgramData\\update.mar"),
+                }]
+            } else if cmd_args.len() % 2 == 0 {
+                cmd_args
+                    .chunks(2)
+                    .map(|pair| FileSpec {
+                        url: pair[0].clone(),
+                        save_path: pair[1].clone(),
+                    }).collect()
+            } else {
+                return Err("bits-start takes a URL and a save path for each file".to_string());
+            };
+
+            let properties = JobProperties {
+                display_name: OsString::from(EXE_NAME),
+                description: OsString::new(),
+                priority: JobPriority::Normal,
+                proxy: ProxySettings::Preconfig,
+            };
+
+            let mut client = connect_client()?;
+            let (guid, monitor) = client.start_job(files, properties)?;
+            println!("start success, guid = {}", guid);
+            print_monitor(monitor)?;
         } else {
-            return Err("bits-start takes no arguments".to_string());
+            return Err("bits-start takes at least 1 argument".to_string());
         },
         "bits-monitor" => if cmd_args.len() == 1 {
-            client::run(&task_name, |c| {
-                client::bits_monitor(c, Guid::from_str(&cmd_args[0].to_string_lossy())?)?;
-                Ok(())
-            })?;
+            let guid = Guid::from_str(&cmd_args[0].to_string_lossy())?;
+            let mut client = connect_client()?;
+            let monitor = client.monitor_job(guid)?;
+            println!("monitor success");
+            print_monitor(monitor)?;
         } else {
             return Err("bits-monitor takes 1 argument".to_string());
         },
         "bits-cancel" => {
-            client::run(&task_name, |c| {
-                for guid in cmd_args
-                    .iter()
-                    .map(|arg| Guid::from_str(&arg.to_string_lossy()))
-                {
-                    client::bits_cancel(c, guid?)?;
-                }
-                Ok(())
-            })?;
+            let mut client = connect_client()?;
+            for arg in cmd_args {
+                client.cancel_job(Guid::from_str(&arg.to_string_lossy())?)?;
+            }
         }
-        "task" => if let Err(s) = server::run(cmd_args) {
-            // debug log
-            File::create("C:\\ProgramData\\taskfail.log")
-                .unwrap()
-                .write(s.to_string().as_bytes())
-                .unwrap();
-            return Err(s);
+        "bits-suspend" => if cmd_args.len() == 1 {
+            let guid = Guid::from_str(&cmd_args[0].to_string_lossy())?;
+            connect_client()?.suspend_job(guid)?;
+        } else {
+            return Err("bits-suspend takes 1 argument".to_string());
+        },
+        "bits-resume" => if cmd_args.len() == 1 {
+            let guid = Guid::from_str(&cmd_args[0].to_string_lossy())?;
+            connect_client()?.resume_job(guid)?;
+        } else {
+            return Err("bits-resume takes 1 argument".to_string());
+        },
+        "bits-stop-monitor" => if cmd_args.len() == 2 {
+            let guid = Guid::from_str(&cmd_args[0].to_string_lossy())?;
+            connect_client()?.stop_monitor(guid, cmd_args[1].clone())?;
+        } else {
+            return Err("bits-stop-monitor takes a GUID and a monitor pipe name".to_string());
+        },
+        "bits-list" => if cmd_args.len() <= 1 {
+            let mut client = connect_client()?;
+            for job in client.list_jobs(cmd_args.get(0).cloned(), Vec::new())? {
+                println!("{} {:?} {:?}", job.guid, job.display_name, job.status);
+            }
+        } else {
+            return Err("bits-list takes at most 1 argument".to_string());
         },
+        "bits-set-priority" => if cmd_args.len() == 2 {
+            let guid = Guid::from_str(&cmd_args[0].to_string_lossy())?;
+            let priority = parse_priority(&cmd_args[1].to_string_lossy())
+                .ok_or_else(|| "priority must be foreground, high, normal or low".to_string())?;
+
+            connect_client()?.set_job_priority(guid, priority)?;
+        } else {
+            return Err("bits-set-priority takes 2 arguments".to_string());
+        },
+        "bits-set-proxy" => if cmd_args.len() >= 2 && cmd_args.len() <= 3 {
+            let guid = Guid::from_str(&cmd_args[0].to_string_lossy())?;
+            let proxy = match &*cmd_args[1].to_string_lossy() {
+                "preconfig" if cmd_args.len() == 2 => ProxySettings::Preconfig,
+                "none" if cmd_args.len() == 2 => ProxySettings::NoProxy,
+                _ => ProxySettings::Override {
+                    proxy_list: cmd_args[1].clone(),
+                    bypass_list: cmd_args.get(2).cloned(),
+                },
+            };
+
+            connect_client()?.set_job_proxy(guid, proxy)?;
+        } else {
+            return Err(
+                "bits-set-proxy takes a GUID and preconfig, none, or a proxy list and optional \
+                 bypass list"
+                    .to_string(),
+            );
+        },
+        "task" => server::run(&task_name, cmd_args)?,
         _ => return Err("Unknown command.".to_string()),
     })
 }
+
+#[cfg(windows)]
+fn env_ms(var: &str, default: u64) -> Result<u64, String> {
+    match env::var_os(var) {
+        None => Ok(default),
+        Some(value) => value
+            .to_string_lossy()
+            .parse::<u64>()
+            .map_err(|_| format!("{} must be a number of milliseconds", var)),
+    }
+}
+
+#[cfg(windows)]
+fn connect(
+    task_name: &OsStr,
+    idle_timeout_ms: u64,
+    connect_timeout: Duration,
+    forward_logs: Option<LogLevel>,
+) -> Result<Client, String> {
+    let mut client = BitsClient::connect(
+        NamedPipeTransport,
+        task_name,
+        idle_timeout_ms,
+        connect_timeout,
+    )?;
+    let logs = match forward_logs {
+        Some(level) => Some(client.forward_logs(level)?),
+        None => None,
+    };
+
+    Ok(Client {
+        client: Some(client),
+        logs,
+    })
+}
+
+/// A connected client, which prints the forwarded server logs when dropped.
+#[cfg(windows)]
+struct Client {
+    client: Option<BitsClient<NamedPipeTransport>>,
+    logs: Option<LogStream<InboundPipeServer>>,
+}
+
+#[cfg(windows)]
+impl Deref for Client {
+    type Target = BitsClient<NamedPipeTransport>;
+
+    fn deref(&self) -> &Self::Target {
+        self.client.as_ref().unwrap()
+    }
+}
+
+#[cfg(windows)]
+impl DerefMut for Client {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.client.as_mut().unwrap()
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Client {
+    fn drop(&mut self) {
+        // The logs end once the connection closes.
+        self.client = None;
+        if let Some(logs) = self.logs.take() {
+            for record in logs {
+                match record {
+                    Ok(record) => eprintln!("server: {}", record),
+                    Err(e) => {
+                        eprintln!("forwarding logs failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+fn print_monitor<L>(monitor: JobMonitor<L>) -> Result<(), String>
+where
+    L: Listener,
+    L::Connection: MessageRead,
+{
+    println!("monitor pipe: {}", monitor.pipe_name().to_string_lossy());
+    for message in monitor {
+        match message? {
+            MonitorMessage::Progress(status) => println!("{:?}", status),
+            MonitorMessage::StateChange(status) => println!("state changed: {:?}", status),
+            MonitorMessage::Error(status) => {
+                println!("{:?}", status);
+                return Err("job failed".to_string());
+            }
+            MonitorMessage::Completed(Ok(result)) => {
+                if result.is_partial() {
+                    println!("partially completed, committed {:?}", result.committed);
+                } else {
+                    println!("completed");
+                }
+                if result.unable_to_delete_temp_files {
+                    println!("some temporary files were left behind");
+                }
+            }
+            MonitorMessage::Completed(Err(e)) => return Err(format!("error from server: {}", e)),
+            MonitorMessage::Cancelled => return Err("job was cancelled".to_string()),
+            MonitorMessage::Shutdown(MonitorShutdown::Stopped) => println!("monitor stopped"),
+            MonitorMessage::Shutdown(reason) => {
+                return Err(format!("monitoring stopped: {}", reason))
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn parse_priority(s: &str) -> Option<JobPriority> {
+    match s {
+        "foreground" => Some(JobPriority::Foreground),
+        "high" => Some(JobPriority::High),
+        "normal" => Some(JobPriority::Normal),
+        "low" => Some(JobPriority::Low),
+        _ => None,
+    }
+}
@@ -0,0 +1,487 @@
+// Sending job status to clients.
+//
+// Each monitor writes to its own pipe, from its own thread, at its own pace. Monitors of the same
+// job share one set of BITS callbacks, which pass events on to every monitor of the job. A
+// monitor ends when the job reaches an outcome, when it is stopped with `StopMonitor`, or when
+// its pipe breaks.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bincode::serialize;
+use comical::guid::Guid;
+
+use backend::{DownloadBackend, DownloadJob};
+use framing::Framed;
+use logging::{self, LogContext};
+use protocol::*;
+use transport::{MessageWrite, Transport};
+
+/// Sent from the job callbacks to each monitor.
+#[derive(Clone)]
+enum MonitorEvent {
+    /// Something changed that can wait for the next update, such as bytes transferred.
+    Modified,
+    /// The job changed state or hit an error, to be reported right away.
+    StateChanged,
+    /// The job was transferred and then completed with this result.
+    Completed(result::Result<CompleteResult, CommandFailure>),
+}
+
+/// The monitors of one job.
+#[derive(Default)]
+struct JobMonitors {
+    /// Events for the monitor on each pipe.
+    pipes: HashMap<OsString, Sender<MonitorEvent>>,
+    /// Set once the transferred callback starts completing the job, so that the job being
+    /// acknowledged isn't mistaken for someone else completing it.
+    completing: Arc<AtomicBool>,
+}
+
+type Registry = Arc<Mutex<HashMap<Guid, JobMonitors>>>;
+
+/// Most monitors running at once, each has a thread and a pipe. More fail with `ServerBusy`.
+const MAX_MONITORS: usize = 64;
+
+/// The active monitors, by job and then by pipe.
+#[derive(Clone)]
+pub struct Monitors<B, T> {
+    backend: B,
+    transport: T,
+    jobs: Registry,
+}
+
+impl<B, T> Monitors<B, T>
+where
+    B: DownloadBackend,
+    T: Transport,
+{
+    pub fn new(backend: B, transport: T) -> Self {
+        Monitors {
+            backend,
+            transport,
+            jobs: Default::default(),
+        }
+    }
+
+    /// Start sending the status of `job` to the monitor's pipe.
+    pub fn start(
+        &self,
+        job: &mut B::Job,
+        monitor: &MonitorConfig,
+    ) -> result::Result<(), CommandFailure> {
+        let guid = job.guid()?;
+
+        // Reserve the job's entry first, so that only one start registers the callbacks. Don't
+        // hold the lock while registering, a callback could come in on this thread.
+        let register = match self.jobs.lock().unwrap().entry(guid.clone()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(Default::default());
+                true
+            }
+        };
+        if register {
+            if let Err(failure) = register_callbacks(&self.jobs, job, &guid) {
+                remove_unused(&mut self.jobs.lock().unwrap(), &guid);
+                return Err(failure);
+            }
+        }
+
+        let (tx, rx) = channel();
+        let completing = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let running: usize = jobs.values().map(|job| job.pipes.len()).sum();
+            let duplicate = match jobs.get(&guid) {
+                Some(job_monitors) => job_monitors.pipes.contains_key(&monitor.pipe_name),
+                None => false,
+            };
+            if running >= MAX_MONITORS || duplicate {
+                remove_unused(&mut jobs, &guid);
+                return Err(if duplicate {
+                    CommandFailure::InvalidArgument("already monitoring on this pipe".to_string())
+                } else {
+                    CommandFailure::ServerBusy
+                });
+            }
+            // Another monitor may have ended in the meantime and taken the entry with it.
+            let job_monitors = jobs.entry(guid.clone()).or_insert_with(Default::default);
+            job_monitors.pipes.insert(monitor.pipe_name.clone(), tx);
+            job_monitors.completing.clone()
+        };
+
+        // The callbacks only hear about changes, so a job that was already transferred has to be
+        // completed here. If its status can't be read, the monitor reports that.
+        if let Ok(status) = job.get_status() {
+            if status.state == JobState::Transferred {
+                complete_job(&self.jobs, &guid, job);
+            }
+        }
+
+        let monitors = self.clone();
+        let monitor = monitor.clone();
+        thread::spawn(move || {
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                monitors.run(&guid, &monitor, &rx, &completing);
+            }));
+            monitors.remove(&guid, &monitor.pipe_name);
+            if let Err(e) = result {
+                log_error!(
+                    LogContext::job(&guid),
+                    "monitor panicked: {}",
+                    logging::panic_message(&*e)
+                );
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Whether there are no active monitors.
+    pub fn is_empty(&self) -> bool {
+        self.jobs.lock().unwrap().is_empty()
+    }
+
+    /// Stop the monitor on `pipe_name`, it sends a final `MonitorShutdown::Stopped`.
+    pub fn stop(&self, guid: &Guid, pipe_name: &OsStr) -> result::Result<(), CommandFailure> {
+        // The monitor notices when its events are dropped.
+        match self.remove(guid, pipe_name) {
+            Some(_) => Ok(()),
+            None => Err(CommandFailure::MonitorNotFound),
+        }
+    }
+
+    fn remove(&self, guid: &Guid, pipe_name: &OsStr) -> Option<Sender<MonitorEvent>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let tx = jobs
+            .get_mut(guid)
+            .and_then(|job_monitors| job_monitors.pipes.remove(pipe_name));
+        remove_unused(&mut jobs, guid);
+        tx
+    }
+
+    fn run(
+        &self,
+        guid: &Guid,
+        monitor: &MonitorConfig,
+        rx: &Receiver<MonitorEvent>,
+        completing: &AtomicBool,
+    ) {
+        let context = LogContext::job(guid);
+        let mut pipe = match self.transport.open_outbound(&monitor.pipe_name) {
+            Ok(pipe) => Framed::new(pipe, MAX_MESSAGE),
+            // Nobody to report to.
+            Err(e) => {
+                log_warn!(context, "couldn't open monitor pipe: {}", e);
+                return;
+            }
+        };
+
+        let message = match self.monitor_job(guid, monitor, rx, completing, &mut pipe) {
+            Ok(message) => message,
+            Err(failure) => {
+                log_warn!(context, "monitor failed: {}", failure);
+                MonitorMessage::Shutdown(MonitorShutdown::Failed(failure))
+            }
+        };
+        log_debug!(context, "monitor ended: {:?}", message);
+
+        // If the client has gone away there is nothing more to do, either way the stream is over.
+        #[allow(unused_must_use)]
+        {
+            pipe.write(&mut serialize(&message).unwrap());
+        }
+    }
+
+    /// Write status updates to `pipe` until the job reaches an outcome or the monitor is
+    /// stopped, returns the final message.
+    fn monitor_job<P>(
+        &self,
+        guid: &Guid,
+        monitor: &MonitorConfig,
+        rx: &Receiver<MonitorEvent>,
+        completing: &AtomicBool,
+        pipe: &mut P,
+    ) -> result::Result<MonitorMessage, CommandFailure>
+    where
+        P: MessageWrite,
+    {
+        let interval = Duration::from_millis(monitor.interval_ms as u64);
+        let min_interval = Duration::from_millis(monitor.min_interval_ms as u64);
+
+        let _inited = self.backend.init_thread()?;
+        // Once completed the job can't be found or queried, but the result is on its way.
+        let mut job = match self.backend.get_job(guid) {
+            Ok(job) => job,
+            Err(_) if completing.load(Ordering::SeqCst) => return Ok(wait_for_completion(rx)),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut reported_state = None;
+        loop {
+            let status = match job.get_status() {
+                Ok(status) => status,
+                Err(_) if completing.load(Ordering::SeqCst) => return Ok(wait_for_completion(rx)),
+                Err(e) => return Err(e.into()),
+            };
+
+            match status.state {
+                JobState::Cancelled => return Ok(MonitorMessage::Cancelled),
+                JobState::Error => return Ok(MonitorMessage::Error(status)),
+                JobState::Acknowledged => {
+                    return Ok(if completing.load(Ordering::SeqCst) {
+                        wait_for_completion(rx)
+                    } else {
+                        MonitorMessage::Shutdown(MonitorShutdown::Acknowledged)
+                    })
+                }
+                _ => {}
+            }
+
+            let state = status.state;
+            let message = match reported_state {
+                Some(reported) if reported != state => MonitorMessage::StateChange(status),
+                _ => MonitorMessage::Progress(status),
+            };
+            reported_state = Some(state);
+            pipe.write(&mut serialize(&message).unwrap())?;
+            let sent = Instant::now();
+
+            // Wait until there's something to report: a state change right away, other
+            // modifications once the minimum interval has passed, or nothing by the full
+            // interval.
+            let mut modified = false;
+            loop {
+                let deadline = sent + if modified { min_interval } else { interval };
+                let now = Instant::now();
+                let timeout = if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::from_millis(0)
+                };
+
+                match rx.recv_timeout(timeout) {
+                    Ok(MonitorEvent::Completed(result)) => {
+                        return Ok(MonitorMessage::Completed(result))
+                    }
+                    Ok(MonitorEvent::StateChanged) | Err(RecvTimeoutError::Timeout) => break,
+                    Ok(MonitorEvent::Modified) => modified = true,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return Ok(MonitorMessage::Shutdown(MonitorShutdown::Stopped))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Remove the job's entry if it has no monitors. This leaves the callbacks registered, they do
+/// nothing without any monitors.
+fn remove_unused(jobs: &mut HashMap<Guid, JobMonitors>, guid: &Guid) {
+    let unused = match jobs.get(guid) {
+        Some(job_monitors) => job_monitors.pipes.is_empty(),
+        None => false,
+    };
+    if unused {
+        jobs.remove(guid);
+    }
+}
+
+fn wait_for_completion(rx: &Receiver<MonitorEvent>) -> MonitorMessage {
+    loop {
+        match rx.recv() {
+            Ok(MonitorEvent::Completed(result)) => return MonitorMessage::Completed(result),
+            Ok(_) => {}
+            Err(_) => return MonitorMessage::Shutdown(MonitorShutdown::Stopped),
+        }
+    }
+}
+
+/// Send `event` to every monitor of the job.
+fn broadcast(jobs: &Registry, guid: &Guid, event: MonitorEvent) {
+    if let Some(job_monitors) = jobs.lock().unwrap().get(guid) {
+        for tx in job_monitors.pipes.values() {
+            #[allow(unused_must_use)]
+            {
+                tx.send(event.clone());
+            }
+        }
+    }
+}
+
+/// Complete a transferred job on behalf of its monitors, and send them the result.
+fn complete_job<J>(jobs: &Registry, guid: &Guid, job: &mut J)
+where
+    J: DownloadJob,
+{
+    // Nobody to report to yet, an entry without monitors is only reserved.
+    let completing = match jobs.lock().unwrap().get(guid) {
+        Some(job_monitors) if !job_monitors.pipes.is_empty() => job_monitors.completing.clone(),
+        _ => return,
+    };
+    if completing.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let result = job.complete().map_err(CommandFailure::from);
+    match result {
+        Ok(ref result) if result.is_partial() => log_warn!(
+            LogContext::job(guid),
+            "partially completed, committed {:?}",
+            result.committed
+        ),
+        Ok(_) => log_info!(LogContext::job(guid), "completed"),
+        Err(ref failure) => log_error!(LogContext::job(guid), "completing failed: {}", failure),
+    }
+    broadcast(jobs, guid, MonitorEvent::Completed(result));
+}
+
+fn register_callbacks<J>(
+    jobs: &Registry,
+    job: &mut J,
+    guid: &Guid,
+) -> result::Result<(), CommandFailure>
+where
+    J: DownloadJob,
+{
+    let transferred_jobs = jobs.clone();
+    let transferred_guid = guid.clone();
+    let error_jobs = jobs.clone();
+    let error_guid = guid.clone();
+    let modification_jobs = jobs.clone();
+    let modification_guid = guid.clone();
+
+    let last_state = Mutex::new(job.get_status()?.state);
+    job.register_callbacks(
+        // The job is only completed on behalf of a monitor, which reports the result.
+        Some(Box::new(move |mut job: J| {
+            complete_job(&transferred_jobs, &transferred_guid, &mut job)
+        })),
+        Some(Box::new(move |_job: J, error: BitsJobError| {
+            log_warn!(
+                LogContext::job(&error_guid),
+                "job error {} in context {:?}",
+                error.error,
+                error.context
+            );
+            broadcast(&error_jobs, &error_guid, MonitorEvent::StateChanged);
+        })),
+        // BITS reports progress as well as state changes (such as being suspended or resumed)
+        // as modifications, only the latter skip the minimum interval.
+        Some(Box::new(move |mut job: J| {
+            let state = match job.get_status() {
+                Ok(status) => status.state,
+                Err(_) => return,
+            };
+            let event = {
+                let mut last_state = last_state.lock().unwrap();
+                if *last_state != state {
+                    *last_state = state;
+                    MonitorEvent::StateChanged
+                } else {
+                    MonitorEvent::Modified
+                }
+            };
+            broadcast(&modification_jobs, &modification_guid, event);
+        })),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    use bincode::deserialize;
+
+    use sim::SimBackend;
+    use temp_dir::TempDir;
+    use transport::{Listener, MemoryListener, MemoryTransport, MessageRead};
+
+    #[test]
+    fn failed_start() {
+        let backend = SimBackend::new(env::temp_dir(), None);
+        let transport = MemoryTransport::new();
+        let monitors = Monitors::new(backend.clone(), transport.clone());
+        let listeners: Vec<_> = (0..MAX_MONITORS + 1)
+            .map(|_| transport.inbound_listener().unwrap())
+            .collect();
+        let config = |listener: &MemoryListener| MonitorConfig {
+            pipe_name: listener.name().to_os_string(),
+            interval_ms: 60_000,
+            min_interval_ms: 0,
+        };
+
+        let mut job = backend.create_job(OsStr::new("busy")).unwrap();
+        let guid = job.guid().unwrap();
+        for listener in &listeners[..MAX_MONITORS] {
+            monitors.start(&mut job, &config(listener)).unwrap();
+        }
+        match monitors.start(&mut job, &config(&listeners[0])) {
+            Err(CommandFailure::InvalidArgument(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // A job whose only start failed isn't left behind.
+        let mut other = backend.create_job(OsStr::new("other")).unwrap();
+        match monitors.start(&mut other, &config(&listeners[MAX_MONITORS])) {
+            Err(CommandFailure::ServerBusy) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        let other_guid = other.guid().unwrap();
+        assert!(!monitors.jobs.lock().unwrap().contains_key(&other_guid));
+
+        for listener in &listeners[..MAX_MONITORS] {
+            monitors.stop(&guid, listener.name()).unwrap();
+        }
+        assert!(monitors.is_empty());
+    }
+
+    #[test]
+    fn completed_before_run() {
+        let root = TempDir::new("monitor");
+        root.create_file("update.mar", b"0123456789");
+        let backend = SimBackend::new(root.to_path_buf(), None);
+        let transport = MemoryTransport::new();
+        let monitors = Monitors::new(backend.clone(), transport.clone());
+
+        let mut job = backend.create_job(OsStr::new("test")).unwrap();
+        let guid = job.guid().unwrap();
+        let save_path = root.join("saved.mar");
+        job.add_file(OsStr::new("update.mar"), save_path.as_os_str())
+            .unwrap();
+        job.resume().unwrap();
+        assert!(backend.step(&guid).unwrap());
+
+        // Starting completes the job, so it is already gone when the monitor looks it up.
+        let listener = transport.inbound_listener().unwrap();
+        let config = MonitorConfig {
+            pipe_name: listener.name().to_os_string(),
+            interval_ms: 60_000,
+            min_interval_ms: 0,
+        };
+        monitors.start(&mut job, &config).unwrap();
+        assert!(backend.get_job(&guid).is_err());
+
+        let mut pipe = Framed::new(listener.accept().unwrap(), MAX_MESSAGE);
+        let mut buf = Vec::new();
+        pipe.read_all(&mut buf).unwrap();
+        match deserialize(&buf).unwrap() {
+            MonitorMessage::Completed(Ok(ref result)) if !result.is_partial() => {}
+            m => panic!("unexpected {:?}", m),
+        }
+        assert_eq!(fs::read_to_string(&save_path).unwrap(), "0123456789");
+    }
+}
@@ -1,27 +1,73 @@
 use std::ffi::{CString, OsStr, OsString};
-use std::mem::size_of;
+use std::mem::{self, size_of};
 use std::ptr::null_mut;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use winapi::shared::minwindef::{DWORD, FALSE};
 use winapi::shared::sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorA, SDDL_REVISION_1};
-use winapi::shared::winerror::ERROR_PIPE_CONNECTED;
+use winapi::shared::winerror::{
+    ERROR_BROKEN_PIPE, ERROR_MORE_DATA, ERROR_NO_DATA, ERROR_PIPE_CONNECTED, ERROR_PIPE_LISTENING,
+    ERROR_PIPE_NOT_CONNECTED,
+};
 use winapi::um::fileapi::{CreateFileW, FlushFileBuffers, ReadFile, WriteFile, OPEN_EXISTING};
 use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
 use winapi::um::namedpipeapi::{
-    ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe, SetNamedPipeHandleState,
-    TransactNamedPipe,
+    ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe, PeekNamedPipe,
+    SetNamedPipeHandleState, TransactNamedPipe,
 };
 use winapi::um::winbase::{
-    FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX, PIPE_ACCESS_INBOUND, PIPE_READMODE_MESSAGE,
-    PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_MESSAGE, PIPE_WAIT,
+    GetNamedPipeClientProcessId, GetNamedPipeServerProcessId, FILE_FLAG_FIRST_PIPE_INSTANCE,
+    PIPE_ACCESS_DUPLEX, PIPE_ACCESS_INBOUND, PIPE_NOWAIT, PIPE_READMODE_MESSAGE,
+    PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_MESSAGE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+use winapi::um::winnt::{
+    FILE_READ_ATTRIBUTES, FILE_WRITE_DATA, GENERIC_READ, GENERIC_WRITE, SYNCHRONIZE,
 };
-use winapi::um::winnt::{FILE_READ_ATTRIBUTES, GENERIC_READ, GENERIC_WRITE};
 use wio::wide::ToWide;
 
 use comical::error::{Error, ErrorCode, Result};
 use comical::handle::{HLocal, Handle};
 use comical::{check_api_nonzero, wrap_api_handle};
 
+use transport::{
+    Acceptor, Listener, MessageRead, MessageWrite, PeerProcess, Transact, Transport, MAX_READ_ALL,
+};
+
+/// Win32 named pipes, restricted to the local machine.
+#[derive(Clone)]
+pub struct NamedPipeTransport;
+
+impl Transport for NamedPipeTransport {
+    type DuplexListener = DuplexPipeServer;
+    type DuplexConnection = DuplexPipeConnection;
+    type InboundListener = InboundPipeServer;
+    type InboundConnection = InboundPipeConnection;
+    type InboundAcceptor = InboundPipeAcceptor;
+    type DuplexClient = DuplexPipeClient;
+    type OutboundClient = OutboundPipeClient;
+
+    fn duplex_listener(&self) -> Result<DuplexPipeServer> {
+        DuplexPipeServer::new()
+    }
+
+    fn inbound_listener(&self) -> Result<InboundPipeServer> {
+        InboundPipeServer::new()
+    }
+
+    fn inbound_acceptor(&self, name: &OsStr) -> Result<InboundPipeAcceptor> {
+        InboundPipeAcceptor::new(name)
+    }
+
+    fn open_duplex(&self, name: &OsStr) -> Result<DuplexPipeClient> {
+        DuplexPipeClient::open(name)
+    }
+
+    fn open_outbound(&self, name: &OsStr) -> Result<OutboundPipeClient> {
+        OutboundPipeClient::open(name)
+    }
+}
+
 pub fn format_local_pipe_path(name: &OsStr) -> OsString {
     let mut path = OsString::from("\\\\.\\pipe\\");
     path.push(name);
@@ -40,51 +86,81 @@ impl DuplexPipeServer {
         let (name, pipe) = new_pipe_impl(true)?;
         Ok(DuplexPipeServer { name, pipe })
     }
+}
 
-    pub fn connect<'a>(&'a mut self) -> Result<DuplexPipeConnection<'a>> {
-        connect_pipe_impl(&self.pipe)?;
+impl Listener for DuplexPipeServer {
+    type Connection = DuplexPipeConnection;
 
-        Ok(DuplexPipeConnection {
-            pipe: &mut self.pipe,
-        })
+    fn name(&self) -> &OsStr {
+        &self.name
     }
 
-    pub fn name(&self) -> &OsStr {
-        &self.name
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<bool> {
+        wait_pipe_impl(&self.pipe, timeout)
+    }
+
+    fn accept(self) -> Result<DuplexPipeConnection> {
+        connect_pipe_impl(&self.pipe)?;
+
+        Ok(DuplexPipeConnection { pipe: self.pipe })
     }
 }
 
-pub struct DuplexPipeConnection<'a> {
-    pipe: &'a mut Handle,
+pub struct DuplexPipeConnection {
+    pipe: Handle,
 }
 
-impl<'a> DuplexPipeConnection<'a> {
-    // TODO: handle ERROR_MORE_DATA?
-    pub fn transact<'b>(
-        &mut self,
-        in_buf: &mut [u8],
-        out_buf: &'b mut [u8],
-    ) -> Result<&'b mut [u8]> {
+impl Transact for DuplexPipeConnection {
+    fn transact(&mut self, in_buf: &mut [u8], out: &mut Vec<u8>) -> Result<()> {
+        out.clear();
+        out.resize(READ_CHUNK, 0);
+
         let mut bytes_read = 0;
-        unsafe {
+        let result = unsafe {
             check_api_nonzero!(TransactNamedPipe(
-                **self.pipe,
+                *self.pipe,
                 in_buf.as_mut_ptr() as *mut _,
                 in_buf.len() as DWORD,
-                out_buf.as_mut_ptr() as *mut _,
-                out_buf.len() as DWORD,
+                out.as_mut_ptr() as *mut _,
+                out.len() as DWORD,
                 &mut bytes_read,
                 null_mut(), // lpOverlapped
             ))
-        }?;
-        Ok(&mut out_buf[..bytes_read as usize])
+        }.map(|_| ())
+        .map_err(check_disconnected);
+
+        read_rest_impl(&self.pipe, out, bytes_read as usize, result)
+    }
+}
+
+impl MessageRead for DuplexPipeConnection {
+    fn read<'b>(&mut self, out_buf: &'b mut [u8]) -> Result<&'b mut [u8]> {
+        read_pipe_impl(&self.pipe, out_buf)
+    }
+
+    fn read_all(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        read_pipe_all_impl(&self.pipe, out)
+    }
+}
+
+impl MessageWrite for DuplexPipeConnection {
+    fn write(&mut self, in_buf: &mut [u8]) -> Result<()> {
+        write_pipe_impl(&self.pipe, in_buf)
+    }
+}
+
+impl PeerProcess for DuplexPipeConnection {
+    fn peer_pid(&self) -> Result<Option<u32>> {
+        let mut pid = 0;
+        unsafe { check_api_nonzero!(GetNamedPipeClientProcessId(*self.pipe, &mut pid)) }?;
+        Ok(Some(pid))
     }
 }
 
-impl<'a> Drop for DuplexPipeConnection<'a> {
+impl Drop for DuplexPipeConnection {
     fn drop(&mut self) {
         unsafe {
-            DisconnectNamedPipe(**self.pipe);
+            DisconnectNamedPipe(*self.pipe);
         }
     }
 }
@@ -101,71 +177,165 @@ impl InboundPipeServer {
         let (name, pipe) = new_pipe_impl(false)?;
         Ok(InboundPipeServer { name, pipe })
     }
+}
 
-    pub fn connect<'a>(&'a mut self) -> Result<InboundPipeConnection<'a>> {
-        connect_pipe_impl(&self.pipe)?;
+impl Listener for InboundPipeServer {
+    type Connection = InboundPipeConnection;
 
-        Ok(InboundPipeConnection {
-            pipe: &mut self.pipe,
-        })
+    fn name(&self) -> &OsStr {
+        &self.name
     }
 
-    pub fn name(&self) -> &OsStr {
-        &self.name
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<bool> {
+        wait_pipe_impl(&self.pipe, timeout)
+    }
+
+    fn accept(self) -> Result<InboundPipeConnection> {
+        connect_pipe_impl(&self.pipe)?;
+
+        Ok(InboundPipeConnection { pipe: self.pipe })
     }
 }
 
-pub struct InboundPipeConnection<'a> {
-    pipe: &'a mut Handle,
+pub struct InboundPipeConnection {
+    pipe: Handle,
 }
 
-impl<'a> InboundPipeConnection<'a> {
-    // TODO: handle ERROR_MORE_DATA?
-    pub fn read<'b>(&mut self, out_buf: &'b mut [u8]) -> Result<&'b mut [u8]> {
-        read_pipe_impl(self.pipe, out_buf)
+impl MessageRead for InboundPipeConnection {
+    fn read<'b>(&mut self, out_buf: &'b mut [u8]) -> Result<&'b mut [u8]> {
+        read_pipe_impl(&self.pipe, out_buf)
+    }
+
+    fn read_all(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        read_pipe_all_impl(&self.pipe, out)
     }
 }
 
-impl<'a> Drop for InboundPipeConnection<'a> {
+// The resident server reads each connection's request on its own thread. The pipe handle isn't
+// tied to the thread that created it.
+unsafe impl Send for InboundPipeConnection {}
+
+impl Drop for InboundPipeConnection {
     fn drop(&mut self) {
         unsafe {
-            DisconnectNamedPipe(**self.pipe);
+            DisconnectNamedPipe(*self.pipe);
         }
     }
 }
 
+pub struct InboundPipeAcceptor {
+    name: OsString,
+    /// The instance waiting for the next connection.
+    next: Handle,
+}
+
+impl InboundPipeAcceptor {
+    /// Create an inbound, synchronous, message-mode pipe for local machine use under `name`,
+    /// allowing connections from any authenticated user. Fails if the name is already taken.
+    pub fn new(name: &OsStr) -> Result<Self> {
+        let next = create_pipe_impl(
+            name,
+            false,
+            true,
+            PIPE_UNLIMITED_INSTANCES,
+            &acceptor_sddl(),
+        )?;
+        Ok(InboundPipeAcceptor {
+            name: name.to_os_string(),
+            next,
+        })
+    }
+}
+
+impl Acceptor for InboundPipeAcceptor {
+    type Connection = InboundPipeConnection;
+
+    fn accept(&mut self) -> Result<InboundPipeConnection> {
+        loop {
+            match connect_pipe_impl(&self.next) {
+                Ok(()) => break,
+                // The client already came and went, wait for another.
+                Err(Error::Api(_, ErrorCode::DWord(ERROR_NO_DATA), _)) => unsafe {
+                    DisconnectNamedPipe(*self.next);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Create the next instance before handing this one out, so the name is never free for
+        // anyone else to take.
+        let next = create_pipe_impl(
+            &self.name,
+            false,
+            false,
+            PIPE_UNLIMITED_INSTANCES,
+            &acceptor_sddl(),
+        )?;
+        Ok(InboundPipeConnection {
+            pipe: mem::replace(&mut self.next, next),
+        })
+    }
+}
+
+fn acceptor_sddl() -> CString {
+    // Full access for the owner, and enough for any authenticated user to write. Leave out
+    // GENERIC_WRITE, as that includes FILE_CREATE_PIPE_INSTANCE, which would let them serve
+    // instances of the pipe themselves.
+    CString::new(format!(
+        "D:(A;;GA;;;OW)(A;;{:#010x};;;AU)",
+        FILE_WRITE_DATA | FILE_READ_ATTRIBUTES | SYNCHRONIZE
+    )).unwrap()
+}
+
 fn new_pipe_impl(duplex: bool) -> Result<(OsString, Handle)> {
     // Create a random 32 character name from the hex of a 128-bit random uint.
     let pipe_name = OsString::from(format!("{:032x}", rand::random::<u128>()));
-    let pipe_path = format_local_pipe_path(&pipe_name).to_wide_null();
+
+    let sddl = if duplex {
+        // Allow read/write access by Local Service.
+        CString::new("D:(A;;GRGW;;;LS)")
+    } else {
+        // Allow write access by Local Service (also need to be able to read attributes).
+        CString::new(format!(
+            "D:(A;;{:#010x};;;LS)",
+            GENERIC_WRITE | FILE_READ_ATTRIBUTES
+        ))
+    }.unwrap();
+
+    let pipe = create_pipe_impl(&pipe_name, duplex, true, 1, &sddl)?;
+    Ok((pipe_name, pipe))
+}
+
+/// Create an instance of a local, synchronous, message-mode pipe. Creating the `first` instance
+/// fails if the name is already taken. All instances must have the same `max_instances`.
+fn create_pipe_impl(
+    name: &OsStr,
+    duplex: bool,
+    first: bool,
+    max_instances: DWORD,
+    sddl: &CString,
+) -> Result<Handle> {
+    let pipe_path = format_local_pipe_path(name).to_wide_null();
 
     // Buffer sizes
     let out_buffer_size = if duplex { 0x10000 } else { 0 };
     let in_buffer_size = 0x10000;
 
     // Open mode
-    let open_mode = if duplex {
+    let mut open_mode = if duplex {
         PIPE_ACCESS_DUPLEX
     } else {
         PIPE_ACCESS_INBOUND
-    } | FILE_FLAG_FIRST_PIPE_INSTANCE;
+    };
+    if first {
+        open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+    }
 
     // Pipe mode
     let pipe_mode =
         PIPE_WAIT | PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE | PIPE_REJECT_REMOTE_CLIENTS;
 
     // Build security attributes
-    let sddl = if duplex {
-        // Allow read/write access by Local Service.
-        CString::new("D:(A;;GRGW;;;LS)")
-    } else {
-        // Allow write access by Local Service (also need to be able to read attributes).
-        CString::new(format!(
-            "D:(A;;{:#010x};;;LS)",
-            GENERIC_WRITE | FILE_READ_ATTRIBUTES
-        ))
-    }.unwrap();
-
     let psd = unsafe {
         let mut raw_psd = null_mut();
         check_api_nonzero!(ConvertStringSecurityDescriptorToSecurityDescriptorA(
@@ -183,18 +353,18 @@ fn new_pipe_impl(duplex: bool) -> Result<(OsString, Handle)> {
         bInheritHandle: FALSE,
     };
 
-    Ok((pipe_name, unsafe {
+    Ok(unsafe {
         wrap_api_handle!(CreateNamedPipeW(
             pipe_path.as_ptr(),
             open_mode,
             pipe_mode,
-            1, // nMaxInstances
+            max_instances,
             out_buffer_size,
             in_buffer_size,
             0, // nDefaultTimeOut (50ms default)
             &mut sa,
         ))
-    }?))
+    }?)
 }
 
 fn connect_pipe_impl(pipe: &Handle) -> Result<()> {
@@ -204,9 +374,62 @@ fn connect_pipe_impl(pipe: &Handle) -> Result<()> {
     }
 }
 
-fn read_pipe_impl<'b>(pipe: &Handle, out_buf: &'b mut [u8]) -> Result<&'b mut [u8]> {
-    let mut bytes_read = 0;
+/// How often `wait_pipe_impl` checks for a client.
+const CONNECT_POLL_MS: u64 = 10;
+
+/// Wait up to `timeout` for a client to connect, returns whether one has. The pipes aren't opened
+/// for overlapped I/O, so this polls with the pipe temporarily in nonblocking mode.
+fn wait_pipe_impl(pipe: &Handle, timeout: Duration) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+    set_pipe_mode_impl(pipe, PIPE_READMODE_MESSAGE | PIPE_NOWAIT)?;
+
+    let result = loop {
+        match unsafe { check_api_nonzero!(ConnectNamedPipe(**pipe, null_mut())) } {
+            Ok(_) | Err(Error::Api(_, ErrorCode::DWord(ERROR_PIPE_CONNECTED), _)) => {
+                break Ok(true)
+            }
+            Err(Error::Api(_, ErrorCode::DWord(ERROR_PIPE_LISTENING), _)) => {}
+            Err(e) => break Err(e),
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            break Ok(false);
+        }
+        thread::sleep((deadline - now).min(Duration::from_millis(CONNECT_POLL_MS)));
+    };
+
+    set_pipe_mode_impl(pipe, PIPE_READMODE_MESSAGE | PIPE_WAIT)?;
+    result
+}
+
+fn set_pipe_mode_impl(pipe: &Handle, mut mode: DWORD) -> Result<()> {
     unsafe {
+        check_api_nonzero!(SetNamedPipeHandleState(
+            **pipe,
+            &mut mode,
+            null_mut(), // lpMaxCollectionCount
+            null_mut(), // lpCollectDataTimeout
+        ))
+    }?;
+    Ok(())
+}
+
+/// Report the other end closing the pipe as `Error::Disconnected`.
+fn check_disconnected(error: Error) -> Error {
+    match error {
+        Error::Api(_, ErrorCode::DWord(ERROR_BROKEN_PIPE), _)
+        | Error::Api(_, ErrorCode::DWord(ERROR_NO_DATA), _)
+        | Error::Api(_, ErrorCode::DWord(ERROR_PIPE_NOT_CONNECTED), _) => Error::Disconnected,
+        error => error,
+    }
+}
+
+/// `ReadFile` into `out_buf`, also returns the number of bytes read, as that is still
+/// meaningful when the message didn't fit (`ERROR_MORE_DATA`).
+fn read_file_impl(pipe: &Handle, out_buf: &mut [u8]) -> (Result<()>, usize) {
+    let mut bytes_read = 0;
+    let result = unsafe {
         check_api_nonzero!(ReadFile(
             **pipe,
             out_buf.as_mut_ptr() as *mut _,
@@ -214,8 +437,88 @@ fn read_pipe_impl<'b>(pipe: &Handle, out_buf: &'b mut [u8]) -> Result<&'b mut [u
             &mut bytes_read,
             null_mut(), // lpOverlapped
         ))
-    }?;
-    Ok(&mut out_buf[..bytes_read as usize])
+    };
+    (
+        result.map(|_| ()).map_err(check_disconnected),
+        bytes_read as usize,
+    )
+}
+
+fn read_pipe_impl<'b>(pipe: &Handle, out_buf: &'b mut [u8]) -> Result<&'b mut [u8]> {
+    let (result, bytes_read) = read_file_impl(pipe, out_buf);
+    result?;
+    Ok(&mut out_buf[..bytes_read])
+}
+
+// Initial buffer size for reading whole messages, grown to fit longer ones.
+const READ_CHUNK: usize = 0x1000;
+
+fn read_pipe_all_impl(pipe: &Handle, out: &mut Vec<u8>) -> Result<()> {
+    out.clear();
+    out.resize(READ_CHUNK, 0);
+
+    let (result, bytes_read) = read_file_impl(pipe, out);
+    read_rest_impl(pipe, out, bytes_read, result)
+}
+
+/// Finish reading a message, given the `result` of reading its first `len` bytes into `out`.
+fn read_rest_impl(
+    pipe: &Handle,
+    out: &mut Vec<u8>,
+    mut len: usize,
+    mut result: Result<()>,
+) -> Result<()> {
+    loop {
+        match result {
+            Ok(()) => {
+                out.truncate(len);
+                return Ok(());
+            }
+            // The buffer filled up, the rest of the message is waiting to be read.
+            Err(Error::Api(_, ErrorCode::DWord(ERROR_MORE_DATA), _)) => {}
+            Err(e) => {
+                out.clear();
+                return Err(e);
+            }
+        }
+
+        // Check the full length before allocating for it.
+        let full_len = match message_left_impl(pipe) {
+            Ok(left) => len + left,
+            Err(e) => {
+                out.clear();
+                return Err(e);
+            }
+        };
+        if full_len > MAX_READ_ALL {
+            out.clear();
+            return Err(Error::MessageTooLong {
+                len: full_len,
+                max: MAX_READ_ALL,
+            });
+        }
+
+        out.resize(full_len, 0);
+        let (part_result, bytes_read) = read_file_impl(pipe, &mut out[len..]);
+        result = part_result;
+        len += bytes_read;
+    }
+}
+
+/// How many bytes of the message being read are still waiting in the pipe.
+fn message_left_impl(pipe: &Handle) -> Result<usize> {
+    let mut left = 0;
+    unsafe {
+        check_api_nonzero!(PeekNamedPipe(
+            **pipe,
+            null_mut(), // lpBuffer
+            0,          // nBufferSize
+            null_mut(), // lpBytesRead
+            null_mut(), // lpTotalBytesAvail
+            &mut left,
+        ))
+    }.map_err(check_disconnected)?;
+    Ok(left as usize)
 }
 
 fn write_pipe_impl<'b>(pipe: &Handle, in_buf: &mut [u8]) -> Result<()> {
@@ -228,7 +531,7 @@ fn write_pipe_impl<'b>(pipe: &Handle, in_buf: &mut [u8]) -> Result<()> {
             &mut bytes_written,
             null_mut(), // lpOverlapped
         ))
-    }?;
+    }.map_err(check_disconnected)?;
 
     if bytes_written != in_buf.len() as DWORD {
         Err(Error::Message(format!(
@@ -268,30 +571,30 @@ impl DuplexPipeClient {
             ))
         }?;
 
-        let mut mode = PIPE_READMODE_MESSAGE;
-        unsafe {
-            check_api_nonzero!(SetNamedPipeHandleState(
-                *pipe,
-                &mut mode,
-                null_mut(), // lpMaxCollectionCount
-                null_mut(), // lpCollectDataTimeout
-            ))
-        }?;
+        set_pipe_mode_impl(&pipe, PIPE_READMODE_MESSAGE)?;
 
         Ok(DuplexPipeClient { pipe })
     }
 
-    pub fn read<'b>(&mut self, out_buf: &'b mut [u8]) -> Result<&'b mut [u8]> {
+    #[allow(dead_code)]
+    pub fn flush(&mut self) -> Result<()> {
+        flush_pipe_impl(&self.pipe)
+    }
+}
+
+impl MessageRead for DuplexPipeClient {
+    fn read<'b>(&mut self, out_buf: &'b mut [u8]) -> Result<&'b mut [u8]> {
         read_pipe_impl(&self.pipe, out_buf)
     }
 
-    pub fn write(&mut self, in_buf: &mut [u8]) -> Result<()> {
-        write_pipe_impl(&self.pipe, in_buf)
+    fn read_all(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        read_pipe_all_impl(&self.pipe, out)
     }
+}
 
-    #[allow(dead_code)]
-    pub fn flush(&mut self) -> Result<()> {
-        flush_pipe_impl(&self.pipe)
+impl MessageWrite for DuplexPipeClient {
+    fn write(&mut self, in_buf: &mut [u8]) -> Result<()> {
+        write_pipe_impl(&self.pipe, in_buf)
     }
 }
 
@@ -303,10 +606,12 @@ impl OutboundPipeClient {
     pub fn open(name: &OsStr) -> Result<Self> {
         let pipe_path = format_local_pipe_path(name).to_wide_null();
 
+        // Only what the pipes grant, see `acceptor_sddl`. Reading attributes is needed for
+        // `peer_pid`.
         let pipe = unsafe {
             wrap_api_handle!(CreateFileW(
                 pipe_path.as_ptr(),
-                GENERIC_WRITE,
+                FILE_WRITE_DATA | FILE_READ_ATTRIBUTES | SYNCHRONIZE,
                 0,          // dwShareMode
                 null_mut(), // lpSecurityAttributes
                 OPEN_EXISTING,
@@ -318,12 +623,22 @@ impl OutboundPipeClient {
         Ok(OutboundPipeClient { pipe })
     }
 
-    pub fn write<'b>(&mut self, in_buf: &mut [u8]) -> Result<()> {
-        write_pipe_impl(&self.pipe, in_buf)
-    }
-
     #[allow(dead_code)]
     pub fn flush(&mut self) -> Result<()> {
         flush_pipe_impl(&self.pipe)
     }
 }
+
+impl MessageWrite for OutboundPipeClient {
+    fn write(&mut self, in_buf: &mut [u8]) -> Result<()> {
+        write_pipe_impl(&self.pipe, in_buf)
+    }
+}
+
+impl PeerProcess for OutboundPipeClient {
+    fn peer_pid(&self) -> Result<Option<u32>> {
+        let mut pid = 0;
+        unsafe { check_api_nonzero!(GetNamedPipeServerProcessId(*self.pipe, &mut pid)) }?;
+        Ok(Some(pid))
+    }
+}
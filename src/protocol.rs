@@ -1,37 +1,151 @@
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::result;
 
 use comical::guid::Guid;
 use serde::{Deserialize, Serialize};
 use serde_derive::{Deserialize, Serialize};
-use winapi::ctypes::{c_uchar, c_ulong, c_ushort};
-use winapi::shared::basetsd::UINT64;
-use winapi::shared::guiddef::GUID;
-use winapi::shared::minwindef::ULONG;
-use winapi::shared::winerror::HRESULT;
-use winapi::um::bits::{BG_ERROR_CONTEXT, BG_JOB_PROGRESS, BG_JOB_STATE};
-
-// TODO: real sizes
-pub const MAX_COMMAND: usize = 0x4000;
-pub const MAX_RESPONSE: usize = 128;
-// TODO: version
-//pub const PROTOCOL_VERSION: u8 = 1;
-
-#[allow(non_snake_case)]
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "GUID")]
-#[repr(C)]
-struct GUIDSerde {
-    pub Data1: c_ulong,
-    pub Data2: c_ushort,
-    pub Data3: c_ushort,
-    pub Data4: [c_uchar; 8],
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "Guid")]
-#[repr(transparent)]
-struct GuidSerde(#[serde(with = "GUIDSerde")] pub GUID);
+
+// Everything here is plain Rust, so that other tools can speak the protocol on any platform.
+// Conversions from the BITS types are in `bits`.
+
+/// Longest message either side sends or accepts after the `Hello`s, which are framed (see
+/// `framing`) from then on.
+pub const MAX_MESSAGE: usize = 0x10_0000;
+
+// Authentication
+//
+// The client makes up a new token for each connection, and passes it to the server in the task
+// arguments or its `ConnectRequest`. The server's first message on the control pipe is the token,
+// so the client knows that it is talking to the server it started or asked for, not some other
+// process that found the pipe.
+
+/// Length of a token. Tokens are random hex strings.
+pub const TOKEN_LEN: usize = 32;
+
+// Version negotiation
+//
+// After the token, the server sends a `Hello` on the control pipe and the client replies with
+// its own. Each side then picks the highest version both support, and the capabilities
+// both have. The `Hello` layout must never change, so that any two versions can at least agree
+// that they can't talk to each other.
+//
+// A new command only needs a capability, as older peers never send it and don't advertise it.
+// Raise `MIN_PROTOCOL_VERSION` only when this build can no longer talk to older peers, and
+// wherever versions behave differently, check `Negotiated::version` or the capabilities.
+
+/// Newest protocol version this build speaks. Bump when any message changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 12;
+/// Oldest protocol version this build still speaks. Version 11 only lacks
+/// `CAPABILITY_FORWARD_LOGS`; before that there was no token.
+pub const MIN_PROTOCOL_VERSION: u32 = 11;
+
+/// Optional features, as bit flags.
+pub type Capabilities = u32;
+/// `Command::ListJobs` is understood.
+pub const CAPABILITY_LIST_JOBS: Capabilities = 1 << 0;
+/// `Command::SetJobPriority` is understood.
+pub const CAPABILITY_SET_JOB_PRIORITY: Capabilities = 1 << 1;
+/// `Command::SetProxy` is understood.
+pub const CAPABILITY_SET_PROXY: Capabilities = 1 << 2;
+/// `Command::SuspendJob` and `Command::ResumeJob` are understood.
+pub const CAPABILITY_SUSPEND_RESUME: Capabilities = 1 << 3;
+/// `Command::StopMonitor` is understood.
+pub const CAPABILITY_STOP_MONITOR: Capabilities = 1 << 4;
+/// `Command::ForwardLogs` is understood.
+pub const CAPABILITY_FORWARD_LOGS: Capabilities = 1 << 5;
+/// Capabilities this build supports.
+pub const CAPABILITIES: Capabilities = CAPABILITY_LIST_JOBS
+    | CAPABILITY_SET_JOB_PRIORITY
+    | CAPABILITY_SET_PROXY
+    | CAPABILITY_SUSPEND_RESUME
+    | CAPABILITY_STOP_MONITOR
+    | CAPABILITY_FORWARD_LOGS;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Hello {
+    pub min_version: u32,
+    pub max_version: u32,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new() -> Hello {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES,
+        }
+    }
+}
+
+/// The outcome of exchanging `Hello`s.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Negotiated {
+    pub version: u32,
+    pub capabilities: Capabilities,
+}
+
+/// The client and server have no protocol version in common.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IncompatibleVersion {
+    pub ours: (u32, u32),
+    pub theirs: (u32, u32),
+}
+
+impl fmt::Display for IncompatibleVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "incompatible protocol versions: we support {}-{}, peer supports {}-{}",
+            self.ours.0, self.ours.1, self.theirs.0, self.theirs.1
+        )
+    }
+}
+
+pub fn negotiate(ours: &Hello, theirs: &Hello) -> result::Result<Negotiated, IncompatibleVersion> {
+    let version = ours.max_version.min(theirs.max_version);
+    if version < ours.min_version || version < theirs.min_version {
+        return Err(IncompatibleVersion {
+            ours: (ours.min_version, ours.max_version),
+            theirs: (theirs.min_version, theirs.max_version),
+        });
+    }
+
+    Ok(Negotiated {
+        version,
+        capabilities: ours.capabilities & theirs.capabilities,
+    })
+}
+
+// Resident server
+//
+// A resident server also listens on an inbound pipe named after the task. Rather than starting
+// the task, a client can write a `ConnectRequest` there, and the server connects to the client's
+// control pipe as if it had been started for it. Fields may only be added at the end, older
+// servers ignore them.
+
+/// The pipe a resident server listens on for `ConnectRequest`s.
+pub fn resident_pipe_name(task_name: &OsStr) -> OsString {
+    let mut name = task_name.to_os_string();
+    name.push("-resident");
+    name
+}
+
+/// How long a shutting down resident server waits for requests from clients that had already
+/// connected. A client gives up on a resident server that hasn't connected back by then.
+pub const LATE_REQUEST_TIMEOUT_MS: u64 = 1000;
+
+/// Longest `ConnectRequest` a resident server accepts.
+pub const MAX_CONNECT_REQUEST: usize = 0x1000;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConnectRequest {
+    /// The client's control pipe.
+    pub pipe_name: OsString,
+    /// To present on the control pipe, see `TOKEN_LEN`.
+    pub token: String,
+}
 
 // Any command
 #[derive(Debug, Deserialize, Serialize)]
@@ -39,6 +153,52 @@ pub enum Command {
     StartJob(StartJobCommand),
     MonitorJob(MonitorJobCommand),
     CancelJob(CancelJobCommand),
+    ListJobs(ListJobsCommand),
+    SetJobPriority(SetJobPriorityCommand),
+    SetProxy(SetProxyCommand),
+    SuspendJob(SuspendJobCommand),
+    ResumeJob(ResumeJobCommand),
+    StopMonitor(StopMonitorCommand),
+    ForwardLogs(ForwardLogsCommand),
+}
+
+/// Why a command failed, returned in place of the command's success type.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum CommandFailure {
+    /// There is no job with the requested GUID.
+    JobNotFound,
+    /// The task isn't allowed to access the job.
+    AccessDenied,
+    /// A BITS call failed, `context` is the API that was called.
+    Bits {
+        context: String,
+        hresult: HResult,
+    },
+    InvalidArgument(String),
+    /// The server can't take on the command right now, it may succeed if retried.
+    ServerBusy,
+    Internal(String),
+    /// There is no monitor of the job on the given pipe.
+    MonitorNotFound,
+    /// The response would have been longer than `MAX_MESSAGE`.
+    ResponseTooLong,
+}
+
+impl fmt::Display for CommandFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandFailure::JobNotFound => f.write_str("job not found"),
+            CommandFailure::AccessDenied => f.write_str("access denied"),
+            CommandFailure::Bits { context, hresult } => {
+                write!(f, "{} failed. hr = {}", context, hresult)
+            }
+            CommandFailure::InvalidArgument(ref msg) => write!(f, "invalid argument: {}", msg),
+            CommandFailure::ServerBusy => f.write_str("server busy"),
+            CommandFailure::Internal(ref msg) => write!(f, "internal error: {}", msg),
+            CommandFailure::MonitorNotFound => f.write_str("monitor not found"),
+            CommandFailure::ResponseTooLong => f.write_str("response too long"),
+        }
+    }
 }
 
 pub trait CommandType<'a, 'b, 'c>: Deserialize<'a> + Serialize {
@@ -50,27 +210,71 @@ pub trait CommandType<'a, 'b, 'c>: Deserialize<'a> + Serialize {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MonitorConfig {
     pub pipe_name: OsString,
+    /// Send an update at least this often, even if BITS hasn't reported any changes.
     pub interval_ms: u32,
+    /// Send progress updates at most this often, changes in between are coalesced. State
+    /// changes are sent right away regardless.
+    pub min_interval_ms: u32,
 }
 
 // Start
-#[derive(Debug, Deserialize, Serialize)]
-pub struct StartJobCommand {
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FileSpec {
     pub url: OsString,
     pub save_path: OsString,
+}
+
+/// Properties of a new job. These are checked by the server against the limits BITS imposes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JobProperties {
+    /// Up to 256 characters, can't be empty.
+    pub display_name: OsString,
+    /// Up to 1024 characters.
+    pub description: OsString,
+    pub priority: JobPriority,
+    pub proxy: ProxySettings,
+}
+
+/// See `BG_JOB_PRIORITY`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum JobPriority {
+    Foreground,
+    High,
+    Normal,
+    Low,
+}
+
+/// How a job reaches the server, see `IBackgroundCopyJob::SetProxySettings`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ProxySettings {
+    /// Use the system proxy settings, the default for new jobs.
+    Preconfig,
+    /// Connect directly.
+    NoProxy,
+    Override {
+        /// Space-delimited proxies, each `[<scheme>=][<scheme>://]<server>[:<port>]`.
+        proxy_list: OsString,
+        /// Space-delimited hosts to connect to directly, `<local>` for any host without a dot.
+        bypass_list: Option<OsString>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StartJobCommand {
+    /// All files are transferred as one job, at least one is required.
+    pub files: Vec<FileSpec>,
+    pub properties: JobProperties,
     pub monitor: Option<MonitorConfig>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct StartJobSuccess {
-    #[serde(with = "GuidSerde")]
     pub guid: Guid,
 }
 
 impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for StartJobCommand {
     type Success = StartJobSuccess;
-    // TODO FIXME temporary hack
-    type Failure = String;
+    type Failure = CommandFailure;
     fn new(cmd: Self) -> Command {
         Command::StartJob(cmd)
     }
@@ -79,7 +283,6 @@ impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for StartJobCommand {
 // Monitor
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MonitorJobCommand {
-    #[serde(with = "GuidSerde")]
     pub guid: Guid,
     pub monitor: Option<MonitorConfig>,
 }
@@ -89,7 +292,7 @@ pub struct MonitorJobSuccess();
 
 impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for MonitorJobCommand {
     type Success = MonitorJobSuccess;
-    type Failure = String;
+    type Failure = CommandFailure;
     fn new(cmd: Self) -> Command {
         Command::MonitorJob(cmd)
     }
@@ -98,7 +301,6 @@ impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for MonitorJobCommand {
 // Cancel
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CancelJobCommand {
-    #[serde(with = "GuidSerde")]
     pub guid: Guid,
 }
 
@@ -107,58 +309,377 @@ pub struct CancelJobSuccess();
 
 impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for CancelJobCommand {
     type Success = CancelJobSuccess;
-    type Failure = String;
+    type Failure = CommandFailure;
     fn new(cmd: Self) -> Command {
         Command::CancelJob(cmd)
     }
 }
 
-// Status reports
+// List
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ListJobsCommand {
+    /// Only list jobs with display names starting with this.
+    pub name_prefix: Option<OsString>,
+    /// Only list jobs in one of these states, or any state if empty.
+    pub states: Vec<JobState>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JobInfo {
+    pub guid: Guid,
+    pub display_name: OsString,
+    pub status: BitsJobStatus,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ListJobsSuccess {
+    pub jobs: Vec<JobInfo>,
+}
+
+impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for ListJobsCommand {
+    type Success = ListJobsSuccess;
+    type Failure = CommandFailure;
+    fn new(cmd: Self) -> Command {
+        Command::ListJobs(cmd)
+    }
+}
+
+// Set priority
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetJobPriorityCommand {
+    pub guid: Guid,
+    pub priority: JobPriority,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetJobPrioritySuccess();
+
+impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for SetJobPriorityCommand {
+    type Success = SetJobPrioritySuccess;
+    type Failure = CommandFailure;
+    fn new(cmd: Self) -> Command {
+        Command::SetJobPriority(cmd)
+    }
+}
+
+// Set proxy
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetProxyCommand {
+    pub guid: Guid,
+    pub proxy: ProxySettings,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetProxySuccess();
+
+impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for SetProxyCommand {
+    type Success = SetProxySuccess;
+    type Failure = CommandFailure;
+    fn new(cmd: Self) -> Command {
+        Command::SetProxy(cmd)
+    }
+}
+
+// Suspend
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SuspendJobCommand {
+    pub guid: Guid,
+}
 
-#[allow(non_snake_case)]
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "BG_JOB_PROGRESS")]
-#[repr(C)]
-pub struct BG_JOB_PROGRESS_Serde {
-    pub BytesTotal: UINT64,
-    pub BytesTransferred: UINT64,
-    pub FilesTotal: ULONG,
-    pub FilesTransferred: ULONG,
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SuspendJobSuccess();
+
+impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for SuspendJobCommand {
+    type Success = SuspendJobSuccess;
+    type Failure = CommandFailure;
+    fn new(cmd: Self) -> Command {
+        Command::SuspendJob(cmd)
+    }
+}
+
+// Resume
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResumeJobCommand {
+    pub guid: Guid,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResumeJobSuccess();
+
+impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for ResumeJobCommand {
+    type Success = ResumeJobSuccess;
+    type Failure = CommandFailure;
+    fn new(cmd: Self) -> Command {
+        Command::ResumeJob(cmd)
+    }
+}
+
+// Stop monitor
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StopMonitorCommand {
+    pub guid: Guid,
+    /// The pipe the monitor was started with.
+    pub pipe_name: OsString,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+pub struct StopMonitorSuccess();
+
+impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for StopMonitorCommand {
+    type Success = StopMonitorSuccess;
+    type Failure = CommandFailure;
+    fn new(cmd: Self) -> Command {
+        Command::StopMonitor(cmd)
+    }
+}
+
+// Forward logs
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ForwardLogsCommand {
+    /// The server connects here and sends `LogRecord`s about this control connection, until it
+    /// closes.
+    pub pipe_name: OsString,
+    /// Send records at this level and above.
+    pub level: LogLevel,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ForwardLogsSuccess();
+
+impl<'a, 'b, 'c> CommandType<'a, 'b, 'c> for ForwardLogsCommand {
+    type Success = ForwardLogsSuccess;
+    type Failure = CommandFailure;
+    fn new(cmd: Self) -> Command {
+        Command::ForwardLogs(cmd)
+    }
+}
+
+// Status reports
+
+/// An `HRESULT`, as reported by BITS.
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HResult(pub i32);
+
+impl fmt::Display for HResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#010x}", self.0)
+    }
+}
+
+impl fmt::Debug for HResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HResult({})", self)
+    }
+}
+
+/// See `BG_JOB_STATE`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum JobState {
+    Queued,
+    Connecting,
+    Transferring,
+    Suspended,
+    Error,
+    TransientError,
+    Transferred,
+    Acknowledged,
+    Cancelled,
+    /// A state this build doesn't know about.
+    Other(u32),
+}
+
+/// Where an error happened, see `BG_ERROR_CONTEXT`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ErrorContext {
+    None,
+    Unknown,
+    GeneralQueueManager,
+    QueueManagerNotification,
+    LocalFile,
+    RemoteFile,
+    GeneralTransport,
+    RemoteApplication,
+    /// A context this build doesn't know about.
+    Other(u32),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct BitsJobError {
-    pub context: BG_ERROR_CONTEXT,
-    pub error: HRESULT,
+    pub context: ErrorContext,
+    pub error: HResult,
+}
+
+/// Progress of the whole job, see `BG_JOB_PROGRESS`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct JobProgress {
+    /// `u64::max_value()` if not known yet.
+    pub bytes_total: u64,
+    pub bytes_transferred: u64,
+    pub files_total: u32,
+    pub files_transferred: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BitsFileProgress {
+    pub bytes_total: u64,
+    pub bytes_transferred: u64,
+    pub completed: bool,
+}
+
+/// The outcome of completing a job.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CompleteResult {
+    /// Whether each file was committed to its save path, in the order they were added to the
+    /// job. Files that hadn't finished transferring are discarded.
+    pub committed: Vec<bool>,
+    /// BITS couldn't delete some of its temporary files, the committed files are still usable.
+    pub unable_to_delete_temp_files: bool,
+}
+
+impl CompleteResult {
+    pub fn is_partial(&self) -> bool {
+        self.committed.iter().any(|committed| !committed)
+    }
+}
+
+/// Sent on a monitor pipe.
+///
+/// The stream ends with exactly one final message (see `is_final`), after which the server
+/// closes the pipe.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum MonitorMessage {
+    /// Periodic update while the job is in the same state.
+    Progress(BitsJobStatus),
+    /// The job moved to a new state, such as being suspended or resumed.
+    StateChange(BitsJobStatus),
+    /// The job stopped in the error state. BITS keeps the job, so it can still be resumed (and
+    /// monitored again) or cancelled. Final.
+    Error(BitsJobStatus),
+    /// The job was transferred and the server completed it. Final.
+    Completed(result::Result<CompleteResult, CommandFailure>),
+    /// The job was cancelled. Final.
+    Cancelled,
+    /// The server stopped monitoring for some other reason. Final.
+    Shutdown(MonitorShutdown),
+}
+
+impl MonitorMessage {
+    /// Whether this is the last message on the monitor pipe.
+    pub fn is_final(&self) -> bool {
+        match self {
+            MonitorMessage::Progress(_) | MonitorMessage::StateChange(_) => false,
+            MonitorMessage::Error(_)
+            | MonitorMessage::Completed(_)
+            | MonitorMessage::Cancelled
+            | MonitorMessage::Shutdown(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum MonitorShutdown {
+    /// The job was completed by someone else, so there is no result to report.
+    Acknowledged,
+    /// Monitoring failed, for instance the job could no longer be queried.
+    Failed(CommandFailure),
+    /// The monitor was stopped with `Command::StopMonitor`.
+    Stopped,
 }
 
-#[derive(Deserialize, Serialize)]
+impl fmt::Display for MonitorShutdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MonitorShutdown::Acknowledged => f.write_str("job was completed elsewhere"),
+            MonitorShutdown::Failed(failure) => write!(f, "monitor failed: {}", failure),
+            MonitorShutdown::Stopped => f.write_str("monitor stopped"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct BitsJobStatus {
-    pub state: BG_JOB_STATE,
-    #[serde(with = "BG_JOB_PROGRESS_Serde")]
-    pub progress: BG_JOB_PROGRESS,
-    pub error_count: ULONG,
+    pub state: JobState,
+    pub progress: JobProgress,
+    /// Progress of each file, in the order they were added to the job.
+    pub files: Vec<BitsFileProgress>,
+    pub error_count: u32,
     pub error: Option<BitsJobError>,
 }
 
-impl fmt::Debug for BitsJobStatus {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "BitsJobStatus {{ ")?;
-        write!(f, "state: {:?}, ", self.state)?;
-        write!(f, "progress: BG_JOB_PROGRESS {{ ")?;
-        write!(f, "BytesTotal: {:?}, ", self.progress.BytesTotal)?;
-        write!(
-            f,
-            "BytesTransferred: {:?}, ",
-            self.progress.BytesTransferred
-        )?;
-        write!(f, "FilesTotal: {:?}, ", self.progress.FilesTotal)?;
-        write!(
-            f,
-            "FilesTransferred: {:?} }}, ",
-            self.progress.FilesTransferred
-        )?;
-        write!(f, "error_count: {:?}, ", self.error_count)?;
-        write!(f, "error: {:?} }}", self.error)
+// Logs
+
+/// How severe a log record is, most severe first.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+/// What a log record is about, to correlate records from different places.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LogContext {
+    /// The server's id for a control connection.
+    pub connection: Option<u64>,
+    /// Counts the commands on a connection.
+    pub request: Option<u64>,
+    pub job: Option<Guid>,
+}
+
+/// Sent on a `ForwardLogs` pipe, see `logging`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LogRecord {
+    /// Milliseconds since the Unix epoch.
+    pub time_ms: u64,
+    pub level: LogLevel,
+    pub context: LogContext,
+    pub message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(min_version: u32, max_version: u32, capabilities: Capabilities) -> Hello {
+        Hello {
+            min_version,
+            max_version,
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn negotiate_versions() {
+        let negotiated = negotiate(&hello(1, 3, 0b011), &hello(2, 5, 0b110)).unwrap();
+        assert_eq!(
+            negotiated,
+            Negotiated {
+                version: 3,
+                capabilities: 0b010,
+            }
+        );
+        assert_eq!(
+            negotiate(&hello(2, 5, 0b110), &hello(1, 3, 0b011)).unwrap(),
+            negotiated
+        );
+
+        assert_eq!(
+            negotiate(&hello(1, 2, 0), &hello(3, 4, 0)),
+            Err(IncompatibleVersion {
+                ours: (1, 2),
+                theirs: (3, 4),
+            })
+        );
+        assert!(negotiate(&hello(3, 4, 0), &hello(1, 2, 0)).is_err());
+    }
+
+    #[test]
+    fn older_peer() {
+        let older = hello(11, 11, CAPABILITIES & !CAPABILITY_FORWARD_LOGS);
+        let negotiated = negotiate(&Hello::new(), &older).unwrap();
+        assert_eq!(negotiated.version, 11);
+        assert_eq!(negotiated.capabilities & CAPABILITY_FORWARD_LOGS, 0);
+        assert!(negotiate(&Hello::new(), &hello(10, 10, CAPABILITIES)).is_err());
     }
 }
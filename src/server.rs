@@ -1,128 +1,1220 @@
-use std::ffi::{OsStr, OsString};
-use std::mem;
+use std::ffi::OsStr;
+#[cfg(windows)]
+use std::ffi::OsString;
+use std::ops::{Deref, DerefMut};
 use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use bincode::{deserialize, serialize};
-use comical::com::ComInited;
+use bincode::{self, deserialize, serialize};
+use comical::error::Error as ComicalError;
 use comical::guid::Guid;
+use serde::Serialize;
 
-use bits::BitsJob;
-use pipe::{DuplexPipeClient, OutboundPipeClient};
+use backend::{DownloadBackend, DownloadJob};
+#[cfg(windows)]
+use bits::BitsBackend;
+use error::{Error, Result};
+use framing::Framed;
+use logging::{self, LogContext};
+use monitor::Monitors;
+#[cfg(windows)]
+use pipe::NamedPipeTransport;
 use protocol::*;
+use transport::{Acceptor, MessageRead, MessageWrite, Transport};
 
-pub fn run(args: &[OsString]) -> result::Result<(), String> {
-    if args[0] == "command-connect" && args.len() == 2 {
-        run_commands(&args[1])
+/// `command-connect <pipe> <idle timeout ms> <token>`: connect to the client's control pipe and
+/// present the token. With a nonzero idle timeout, stay resident to serve other clients as well.
+#[cfg(windows)]
+pub fn run(task_name: &OsStr, args: &[OsString]) -> result::Result<(), String> {
+    if args.len() != 4 || args[0] != "command-connect" {
+        return Err("Bad command".to_string());
+    }
+
+    let idle_timeout_ms = args[2]
+        .to_string_lossy()
+        .parse::<u64>()
+        .map_err(|_| "Bad idle timeout".to_string())?;
+    let token = args[3].to_str().ok_or_else(|| "Bad token".to_string())?;
+
+    log_info!(
+        LogContext::default(),
+        "started, idle timeout {} ms",
+        idle_timeout_ms
+    );
+    if idle_timeout_ms == 0 {
+        run_commands(&BitsBackend, &NamedPipeTransport, &args[1], token)
     } else {
-        Err("Bad command".to_string())
+        run_resident(
+            &BitsBackend,
+            &NamedPipeTransport,
+            &resident_pipe_name(task_name),
+            &args[1],
+            token,
+            Duration::from_millis(idle_timeout_ms),
+        )
     }
 }
 
-fn run_commands(pipe_name: &OsStr) -> result::Result<(), String> {
-    let mut control_pipe = DuplexPipeClient::open(pipe_name)?;
+/// Serve the one client whose control pipe is `pipe_name`, which gave us `token`.
+pub fn run_commands<B, T>(
+    backend: &B,
+    transport: &T,
+    pipe_name: &OsStr,
+    token: &str,
+) -> result::Result<(), String>
+where
+    B: DownloadBackend,
+    T: Transport,
+{
+    let monitors = Monitors::new(backend.clone(), transport.clone());
+    serve_connection(backend, transport, &monitors, pipe_name, token)
+}
+
+/// When a resident server was last busy.
+struct Activity {
+    connections: usize,
+    /// Connections to the resident pipe whose `ConnectRequest` hasn't been read yet. These don't
+    /// keep the server from going idle, a client could hold one open without ever sending.
+    requests: usize,
+    last_active: Instant,
+    /// Set once the server has been idle for `idle_timeout`.
+    shutting_down: bool,
+}
+
+/// How long to wait after an accept fails before trying again.
+const ACCEPT_RETRY_MS: u64 = 100;
+
+/// Serve the client whose control pipe is `pipe_name`, and any others that ask through the
+/// `resident_name` pipe, until there have been no connections or monitors for `idle_timeout`.
+///
+/// If another server is already resident, only `pipe_name` is served.
+pub fn run_resident<B, T>(
+    backend: &B,
+    transport: &T,
+    resident_name: &OsStr,
+    pipe_name: &OsStr,
+    token: &str,
+    idle_timeout: Duration,
+) -> result::Result<(), String>
+where
+    B: DownloadBackend,
+    T: Transport,
+{
+    let monitors = Monitors::new(backend.clone(), transport.clone());
+
+    let mut acceptor = match transport.inbound_acceptor(resident_name) {
+        Ok(acceptor) => acceptor,
+        Err(_) => {
+            log_info!(LogContext::default(), "another server is resident");
+            return serve_connection(backend, transport, &monitors, pipe_name, token);
+        }
+    };
+
+    let activity = Arc::new(Mutex::new(Activity {
+        connections: 0,
+        requests: 0,
+        last_active: Instant::now(),
+        shutting_down: false,
+    }));
+
+    spawn_connection(backend, transport, &monitors, &activity, pipe_name, token);
+
+    {
+        let transport = transport.clone();
+        let monitors = monitors.clone();
+        let activity = activity.clone();
+        let resident_name = resident_name.to_os_string();
+        thread::spawn(move || {
+            let poll_interval = idle_timeout.min(Duration::from_secs(1));
+            loop {
+                thread::sleep(poll_interval);
+                let mut activity = activity.lock().unwrap();
+                if activity.connections > 0 || !monitors.is_empty() {
+                    activity.last_active = Instant::now();
+                } else if activity.last_active.elapsed() >= idle_timeout {
+                    activity.shutting_down = true;
+                    break;
+                }
+            }
+
+            // Wake the accept loop so it can see that it's time to go.
+            log_info!(LogContext::default(), "idle, shutting down");
+            #[allow(unused_must_use)]
+            {
+                transport.open_outbound(&resident_name);
+            }
+        });
+    }
+
+    loop {
+        match acceptor.accept() {
+            Ok(connection) => spawn_request(backend, transport, &monitors, &activity, connection),
+            Err(e) => {
+                log_error!(LogContext::default(), "accept failed: {}", e);
+                thread::sleep(Duration::from_millis(ACCEPT_RETRY_MS));
+            }
+        }
+        if activity.lock().unwrap().shutting_down {
+            break;
+        }
+    }
+    // New clients start another server from here on.
+    drop(acceptor);
 
+    // A client may have gotten in before the wake up, it has already committed to us. Give it a
+    // moment to send its request, and see it through.
+    let deadline = Instant::now() + Duration::from_millis(LATE_REQUEST_TIMEOUT_MS);
     loop {
-        let mut buf: [u8; MAX_COMMAND] = unsafe { mem::uninitialized() };
-        // TODO better handling of errors, not really a disaster if the pipe closes, and
-        // we may want to do something with ERROR_MORE_DATA
-        let buf = control_pipe.read(&mut buf)?;
-
-        // TODO setup logging
-        let deserialized_command = deserialize(buf);
-        let mut serialized_response = match deserialized_command {
+        {
+            let activity = activity.lock().unwrap();
+            let waiting = activity.requests > 0 && Instant::now() < deadline;
+            if !waiting && activity.connections == 0 && monitors.is_empty() {
+                return Ok(());
+            }
+        }
+        thread::sleep(Duration::from_millis(ACCEPT_RETRY_MS));
+    }
+}
+
+fn spawn_connection<B, T>(
+    backend: &B,
+    transport: &T,
+    monitors: &Monitors<B, T>,
+    activity: &Arc<Mutex<Activity>>,
+    pipe_name: &OsStr,
+    token: &str,
+) where
+    B: DownloadBackend,
+    T: Transport,
+{
+    activity.lock().unwrap().connections += 1;
+
+    let backend = backend.clone();
+    let transport = transport.clone();
+    let monitors = monitors.clone();
+    let activity = activity.clone();
+    let pipe_name = pipe_name.to_os_string();
+    let token = token.to_string();
+    thread::spawn(move || {
+        serve_counted(
+            &backend, &transport, &monitors, &activity, &pipe_name, &token,
+        )
+    });
+}
+
+/// Read the `ConnectRequest` from a connection to the resident pipe and serve the client. This
+/// gets its own thread, so that a client that never sends a request doesn't hold up the others.
+fn spawn_request<B, T>(
+    backend: &B,
+    transport: &T,
+    monitors: &Monitors<B, T>,
+    activity: &Arc<Mutex<Activity>>,
+    mut connection: T::InboundConnection,
+) where
+    B: DownloadBackend,
+    T: Transport,
+{
+    activity.lock().unwrap().requests += 1;
+
+    let backend = backend.clone();
+    let transport = transport.clone();
+    let monitors = monitors.clone();
+    let activity = activity.clone();
+    thread::spawn(move || {
+        let mut buf = [0u8; MAX_CONNECT_REQUEST];
+        let request: Option<ConnectRequest> = connection
+            .read(&mut buf)
+            .ok()
+            .and_then(|buf| deserialize(buf).ok());
+        drop(connection);
+
+        {
+            let mut activity = activity.lock().unwrap();
+            activity.requests -= 1;
+            match request {
+                Some(_) => activity.connections += 1,
+                // Nothing to connect to, expected of the wake up.
+                None if !activity.shutting_down => {
+                    log_warn!(LogContext::default(), "bad connect request")
+                }
+                None => {}
+            }
+        }
+
+        if let Some(request) = request {
+            serve_counted(
+                &backend,
+                &transport,
+                &monitors,
+                &activity,
+                &request.pipe_name,
+                &request.token,
+            );
+        }
+    });
+}
+
+/// Serve a client counted in `activity.connections`, which stops counting it when done.
+fn serve_counted<B, T>(
+    backend: &B,
+    transport: &T,
+    monitors: &Monitors<B, T>,
+    activity: &Mutex<Activity>,
+    pipe_name: &OsStr,
+    token: &str,
+) where
+    B: DownloadBackend,
+    T: Transport,
+{
+    let result = backend
+        .init_thread()
+        .map_err(String::from)
+        .and_then(|_inited| serve_connection(backend, transport, monitors, pipe_name, token));
+    if let Err(e) = result {
+        log_error!(LogContext::default(), "connection failed: {}", e);
+    }
+
+    let mut activity = activity.lock().unwrap();
+    activity.connections -= 1;
+    activity.last_active = Instant::now();
+}
+
+fn serve_connection<B, T>(
+    backend: &B,
+    transport: &T,
+    monitors: &Monitors<B, T>,
+    pipe_name: &OsStr,
+    token: &str,
+) -> result::Result<(), String>
+where
+    B: DownloadBackend,
+    T: Transport,
+{
+    let connection = NEXT_CONNECTION.fetch_add(1, Ordering::SeqCst) as u64;
+    let _forwarding = Forwarding(connection);
+    let mut context = LogContext {
+        connection: Some(connection),
+        ..Default::default()
+    };
+
+    let mut control_pipe = transport.open_duplex(pipe_name)?;
+    control_pipe.write(&mut token.as_bytes().to_vec())?;
+    let negotiated = handshake(&mut control_pipe)?;
+    log_debug!(context, "connected, {:?}", negotiated);
+    let mut control_pipe = Framed::new(control_pipe, MAX_MESSAGE);
+
+    let mut buf = Vec::new();
+    let mut request = 0;
+    loop {
+        match control_pipe.read_all(&mut buf) {
+            Ok(()) => {}
+            // The client is done.
+            Err(ComicalError::Disconnected) => {
+                log_debug!(context, "disconnected");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        request += 1;
+        context.request = Some(request);
+        let command: Command = match deserialize(&buf) {
             // TODO response for undeserializable command?
-            Err(_) => return Err("deserialize failed".to_string()),
-            Ok(Command::StartJob(cmd)) => serialize(&run_start(&cmd)),
-            Ok(Command::MonitorJob(cmd)) => serialize(&run_monitor(&cmd)),
-            Ok(Command::CancelJob(cmd)) => serialize(&run_cancel(&cmd)),
+            Err(e) => {
+                log_error!(context, "deserialize failed: {}", e);
+                return Err("deserialize failed".to_string());
+            }
+            Ok(command) => command,
+        };
+        context.job = command_guid(&command).cloned();
+        log_debug!(context, "{:?}", command);
+
+        let mut serialized_response = match command {
+            Command::StartJob(cmd) => {
+                let result = run_start(backend, monitors, &cmd);
+                if let Ok(ref success) = result {
+                    context.job = Some(success.guid.clone());
+                    log_info!(context, "started job");
+                }
+                respond(&context, &result)
+            }
+            Command::MonitorJob(cmd) => respond(&context, &run_monitor(backend, monitors, &cmd)),
+            Command::CancelJob(cmd) => respond(&context, &run_cancel(backend, &cmd)),
+            Command::ListJobs(cmd) => respond(&context, &run_list(backend, &cmd)),
+            Command::SetJobPriority(cmd) => respond(&context, &run_set_priority(backend, &cmd)),
+            Command::SetProxy(cmd) => respond(&context, &run_set_proxy(backend, &cmd)),
+            Command::SuspendJob(cmd) => respond(&context, &run_suspend(backend, &cmd)),
+            Command::ResumeJob(cmd) => respond(&context, &run_resume(backend, &cmd)),
+            Command::StopMonitor(cmd) => respond(&context, &run_stop_monitor(monitors, &cmd)),
+            Command::ForwardLogs(cmd) => {
+                respond(&context, &run_forward_logs(transport, connection, &cmd))
+            }
         }.unwrap();
-        assert!(serialized_response.len() <= MAX_RESPONSE);
 
-        control_pipe.write(&mut serialized_response)?;
+        let result = match control_pipe.write(&mut serialized_response) {
+            Err(ComicalError::MessageTooLong { .. }) => {
+                // A failure is a valid response to any command.
+                let failure: result::Result<(), _> = Err(CommandFailure::ResponseTooLong);
+                control_pipe.write(&mut serialize(&failure).unwrap())
+            }
+            result => result,
+        };
+        match result {
+            Ok(()) => {}
+            // The client left without waiting for the response.
+            Err(ComicalError::Disconnected) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Identifies control connections in logs.
+static NEXT_CONNECTION: AtomicUsize = AtomicUsize::new(1);
+
+/// Stops forwarding logs for a connection when dropped.
+struct Forwarding(u64);
+
+impl Drop for Forwarding {
+    fn drop(&mut self) {
+        logging::stop_forwarding(self.0);
+    }
+}
+
+/// The job a command is about, if any.
+fn command_guid(command: &Command) -> Option<&Guid> {
+    match command {
+        Command::MonitorJob(cmd) => Some(&cmd.guid),
+        Command::CancelJob(cmd) => Some(&cmd.guid),
+        Command::SetJobPriority(cmd) => Some(&cmd.guid),
+        Command::SetProxy(cmd) => Some(&cmd.guid),
+        Command::SuspendJob(cmd) => Some(&cmd.guid),
+        Command::ResumeJob(cmd) => Some(&cmd.guid),
+        Command::StopMonitor(cmd) => Some(&cmd.guid),
+        Command::StartJob(_) | Command::ListJobs(_) | Command::ForwardLogs(_) => None,
+    }
+}
+
+/// Serialize the response to a command, logging failures.
+fn respond<S>(
+    context: &LogContext,
+    result: &result::Result<S, CommandFailure>,
+) -> bincode::Result<Vec<u8>>
+where
+    S: Serialize,
+{
+    if let Err(ref failure) = *result {
+        log_warn!(context, "command failed: {}", failure);
     }
+    serialize(result)
+}
+
+/// Send our `Hello` and receive the client's.
+fn handshake<C>(control_pipe: &mut C) -> Result<Negotiated>
+where
+    C: MessageRead + MessageWrite,
+{
+    let hello = Hello::new();
+    control_pipe.write(&mut serialize(&hello).unwrap())?;
+
+    let mut buf = Vec::new();
+    control_pipe.read_all(&mut buf)?;
+    let client_hello: Hello = match deserialize(&buf) {
+        Err(e) => return Err(Error::Message(format!("deserialize hello failed: {}", e))),
+        Ok(hello) => hello,
+    };
+
+    Ok(negotiate(&hello, &client_hello)?)
 }
 
-fn run_start(cmd: &StartJobCommand) -> result::Result<StartJobSuccess, String> {
-    // TODO: gotta capture, return, log errors
-    let mut job = BitsJob::new(&OsString::from("JOBBO"))?;
-    job.add_file(&cmd.url, &cmd.save_path)?;
+fn run_start<B, T>(
+    backend: &B,
+    monitors: &Monitors<B, T>,
+    cmd: &StartJobCommand,
+) -> result::Result<StartJobSuccess, CommandFailure>
+where
+    B: DownloadBackend,
+    T: Transport,
+{
+    if cmd.files.is_empty() {
+        return Err(CommandFailure::InvalidArgument("no files".to_string()));
+    }
+
+    validate_properties(&cmd.properties)?;
+    if let Some(ref monitor) = cmd.monitor {
+        validate_monitor(monitor)?;
+    }
+
+    let mut job = CancelOnDrop(Some(backend.create_job(&cmd.properties.display_name)?));
+    job.set_description(&cmd.properties.description)?;
+    job.set_priority(cmd.properties.priority)?;
+    job.set_proxy_settings(&cmd.properties.proxy)?;
+    for file in &cmd.files {
+        job.add_file(&file.url, &file.save_path)?;
+    }
     job.resume()?;
 
     if let Some(ref monitor) = cmd.monitor {
-        start_monitor(job.guid()?, monitor);
+        monitors.start(&mut job, monitor)?;
+    }
+    let guid = job.guid()?;
+    job.keep();
+    Ok(StartJobSuccess { guid })
+}
+
+/// Cancels a job being started if it's dropped before `keep`, as when starting fails. Nobody
+/// would know about the job, so don't leave it around.
+struct CancelOnDrop<J>(Option<J>)
+where
+    J: DownloadJob;
+
+impl<J> CancelOnDrop<J>
+where
+    J: DownloadJob,
+{
+    fn keep(mut self) {
+        self.0.take();
+    }
+}
+
+impl<J> Deref for CancelOnDrop<J>
+where
+    J: DownloadJob,
+{
+    type Target = J;
+
+    fn deref(&self) -> &J {
+        self.0.as_ref().unwrap()
     }
-    Ok(StartJobSuccess { guid: job.guid()? })
 }
 
-fn run_monitor(cmd: &MonitorJobCommand) -> result::Result<MonitorJobSuccess, String> {
-    let job = BitsJob::get_by_guid(&cmd.guid)?;
+impl<J> DerefMut for CancelOnDrop<J>
+where
+    J: DownloadJob,
+{
+    fn deref_mut(&mut self) -> &mut J {
+        self.0.as_mut().unwrap()
+    }
+}
 
+impl<J> Drop for CancelOnDrop<J>
+where
+    J: DownloadJob,
+{
+    fn drop(&mut self) {
+        if let Some(mut job) = self.0.take() {
+            #[allow(unused_must_use)]
+            {
+                job.cancel();
+            }
+        }
+    }
+}
+
+// Limits from the IBackgroundCopyJob::SetDisplayName and SetDescription docs, in UTF-16 units.
+const MAX_DISPLAY_NAME: usize = 256;
+const MAX_DESCRIPTION: usize = 1024;
+// From IBackgroundCopyJob::SetProxySettings, for each of the proxy and bypass lists.
+const MAX_PROXY_LIST: usize = 4000;
+
+fn validate_properties(properties: &JobProperties) -> result::Result<(), CommandFailure> {
+    if properties.display_name.is_empty() {
+        return Err(CommandFailure::InvalidArgument(
+            "empty display name".to_string(),
+        ));
+    }
+    validate_string("display name", &properties.display_name, MAX_DISPLAY_NAME)?;
+    validate_string("description", &properties.description, MAX_DESCRIPTION)?;
+    validate_proxy(&properties.proxy)
+}
+
+fn validate_proxy(proxy: &ProxySettings) -> result::Result<(), CommandFailure> {
+    if let ProxySettings::Override {
+        proxy_list,
+        bypass_list,
+    } = proxy
+    {
+        if proxy_list.is_empty() {
+            return Err(CommandFailure::InvalidArgument(
+                "empty proxy list".to_string(),
+            ));
+        }
+        validate_string("proxy list", proxy_list, MAX_PROXY_LIST)?;
+        if let Some(bypass_list) = bypass_list {
+            validate_string("proxy bypass list", bypass_list, MAX_PROXY_LIST)?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_string(what: &str, s: &OsStr, max_len: usize) -> result::Result<(), CommandFailure> {
+    let s = s.to_string_lossy();
+    if s.contains('\0') {
+        return Err(CommandFailure::InvalidArgument(format!("nul in {}", what)));
+    }
+    if s.encode_utf16().count() > max_len {
+        return Err(CommandFailure::InvalidArgument(format!(
+            "{} longer than {} characters",
+            what, max_len
+        )));
+    }
+    Ok(())
+}
+
+fn validate_monitor(monitor: &MonitorConfig) -> result::Result<(), CommandFailure> {
+    if monitor.interval_ms == 0 {
+        return Err(CommandFailure::InvalidArgument(
+            "zero monitor interval".to_string(),
+        ));
+    }
+    if monitor.min_interval_ms > monitor.interval_ms {
+        return Err(CommandFailure::InvalidArgument(
+            "monitor minimum interval longer than interval".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn run_monitor<B, T>(
+    backend: &B,
+    monitors: &Monitors<B, T>,
+    cmd: &MonitorJobCommand,
+) -> result::Result<MonitorJobSuccess, CommandFailure>
+where
+    B: DownloadBackend,
+    T: Transport,
+{
     if let Some(ref monitor) = cmd.monitor {
-        start_monitor(job.guid()?, monitor);
+        validate_monitor(monitor)?;
+    }
+
+    let mut job = backend.get_job(&cmd.guid)?;
+
+    if let Some(ref monitor) = cmd.monitor {
+        monitors.start(&mut job, monitor)?;
     }
     Ok(MonitorJobSuccess())
 }
 
-fn start_monitor(
-    guid: Guid,
-    MonitorConfig {
-        pipe_name,
-        interval_ms,
-    }: &MonitorConfig,
-) {
-    let interval_ms = *interval_ms;
-    let pipe_name = pipe_name.clone();
-    thread::spawn(move || {
-        let result = std::panic::catch_unwind(|| {
-            use std::sync::mpsc::channel;
-            let (tx, rx) = channel();
-
-            // TODO none of this stuff (except serialize) should be `unwrap`
-            let _inited = ComInited::init_mta().unwrap();
-            let mut job = BitsJob::get_by_guid(&guid).unwrap();
-            let mut pipe = OutboundPipeClient::open(&pipe_name).unwrap();
-            let delay = Duration::from_millis(interval_ms as u64);
-
-            let tx_mutex = std::sync::Mutex::new(tx);
-            job.register_callbacks(
-                Some(Box::new(move |mut job| {
-                    job.complete().expect("complete failed?!");
-
-                    let tx = tx_mutex.lock().unwrap().clone();
-
-                    #[allow(unused_must_use)]
-                    {
-                        tx.send(());
-                    }
-                })),
-                None,
-                None,
-            ).unwrap();
+fn run_stop_monitor<B, T>(
+    monitors: &Monitors<B, T>,
+    cmd: &StopMonitorCommand,
+) -> result::Result<StopMonitorSuccess, CommandFailure>
+where
+    B: DownloadBackend,
+    T: Transport,
+{
+    monitors.stop(&cmd.guid, &cmd.pipe_name)?;
+    Ok(StopMonitorSuccess())
+}
 
-            loop {
-                let status = job.get_status().unwrap();
-                pipe.write(&mut serialize(&status).unwrap()).unwrap();
-                #[allow(unused_must_use)]
-                {
-                    rx.recv_timeout(delay);
-                }
+fn run_cancel<B>(
+    backend: &B,
+    cmd: &CancelJobCommand,
+) -> result::Result<CancelJobSuccess, CommandFailure>
+where
+    B: DownloadBackend,
+{
+    let mut job = backend.get_job(&cmd.guid)?;
+    job.cancel()?;
+
+    Ok(CancelJobSuccess())
+}
+
+fn run_forward_logs<T>(
+    transport: &T,
+    connection: u64,
+    cmd: &ForwardLogsCommand,
+) -> result::Result<ForwardLogsSuccess, CommandFailure>
+where
+    T: Transport,
+{
+    let (tx, rx) = channel();
+    logging::forward(connection, cmd.level, tx);
+
+    let transport = transport.clone();
+    let pipe_name = cmd.pipe_name.clone();
+    thread::spawn(move || {
+        // Either way this returns, dropping `rx` ends the forwarding.
+        let mut pipe = match transport.open_outbound(&pipe_name) {
+            Ok(pipe) => Framed::new(pipe, MAX_MESSAGE),
+            Err(_) => return,
+        };
+        for record in rx {
+            if pipe.write(&mut serialize(&record).unwrap()).is_err() {
+                return;
             }
-        });
-        if let Err(e) = result {
-            use std::io::Write;
-            std::fs::File::create("C:\\ProgramData\\monitorfail.log")
-                .unwrap()
-                .write(format!("{:?}", e.downcast_ref::<String>()).as_bytes())
-                .unwrap();
         }
     });
+
+    Ok(ForwardLogsSuccess())
 }
 
-fn run_cancel(cmd: &CancelJobCommand) -> result::Result<CancelJobSuccess, String> {
-    let mut job = BitsJob::get_by_guid(&cmd.guid)?;
-    job.cancel()?;
+fn run_list<B>(
+    backend: &B,
+    cmd: &ListJobsCommand,
+) -> result::Result<ListJobsSuccess, CommandFailure>
+where
+    B: DownloadBackend,
+{
+    let mut jobs = Vec::new();
+    for mut job in backend.list_jobs()? {
+        // A job can go away while we're listing, such as by being completed, so leave out any
+        // that can't be queried rather than failing the whole list.
+        if let Ok(Some(info)) = list_job(&mut job, cmd) {
+            jobs.push(info);
+        }
+    }
 
-    Ok(CancelJobSuccess())
+    Ok(ListJobsSuccess { jobs })
+}
+
+/// Describe `job`, or `None` if the command's filters leave it out.
+fn list_job<J>(job: &mut J, cmd: &ListJobsCommand) -> result::Result<Option<JobInfo>, ComicalError>
+where
+    J: DownloadJob,
+{
+    let display_name = job.display_name()?;
+    if let Some(ref prefix) = cmd.name_prefix {
+        if !display_name
+            .to_string_lossy()
+            .starts_with(&*prefix.to_string_lossy())
+        {
+            return Ok(None);
+        }
+    }
+
+    let status = job.get_status()?;
+    if !cmd.states.is_empty() && !cmd.states.contains(&status.state) {
+        return Ok(None);
+    }
+
+    Ok(Some(JobInfo {
+        guid: job.guid()?,
+        display_name,
+        status,
+    }))
+}
+
+fn run_set_priority<B>(
+    backend: &B,
+    cmd: &SetJobPriorityCommand,
+) -> result::Result<SetJobPrioritySuccess, CommandFailure>
+where
+    B: DownloadBackend,
+{
+    let mut job = backend.get_job(&cmd.guid)?;
+    job.set_priority(cmd.priority)?;
+
+    Ok(SetJobPrioritySuccess())
+}
+
+fn run_set_proxy<B>(
+    backend: &B,
+    cmd: &SetProxyCommand,
+) -> result::Result<SetProxySuccess, CommandFailure>
+where
+    B: DownloadBackend,
+{
+    validate_proxy(&cmd.proxy)?;
+
+    let mut job = backend.get_job(&cmd.guid)?;
+    job.set_proxy_settings(&cmd.proxy)?;
+
+    Ok(SetProxySuccess())
+}
+
+fn run_suspend<B>(
+    backend: &B,
+    cmd: &SuspendJobCommand,
+) -> result::Result<SuspendJobSuccess, CommandFailure>
+where
+    B: DownloadBackend,
+{
+    let mut job = backend.get_job(&cmd.guid)?;
+    job.suspend()?;
+
+    Ok(SuspendJobSuccess())
+}
+
+fn run_resume<B>(
+    backend: &B,
+    cmd: &ResumeJobCommand,
+) -> result::Result<ResumeJobSuccess, CommandFailure>
+where
+    B: DownloadBackend,
+{
+    let mut job = backend.get_job(&cmd.guid)?;
+    job.resume()?;
+
+    Ok(ResumeJobSuccess())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::ffi::OsString;
+    use std::thread::{self, JoinHandle};
+
+    use client::{run_command, BitsClient};
+    use sim::SimBackend;
+    use temp_dir::TempDir;
+    use transport::{Listener, MemoryConnection, MemoryTransport};
+
+    const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+    /// Start a server, check its token and exchange `Hello`s with it.
+    fn start_server() -> (MemoryConnection, JoinHandle<result::Result<(), String>>) {
+        let backend = SimBackend::new(env::temp_dir(), None);
+        let transport = MemoryTransport::new();
+        let listener = transport.duplex_listener().unwrap();
+
+        let server_transport = transport.clone();
+        let name = listener.name().to_os_string();
+        let server = thread::spawn(move || run_commands(&backend, &server_transport, &name, TOKEN));
+
+        let mut connection = listener.accept().unwrap();
+        let mut buf = Vec::new();
+        connection.read_all(&mut buf).unwrap();
+        assert_eq!(buf, TOKEN.as_bytes());
+        connection.read_all(&mut buf).unwrap();
+        let _: Hello = deserialize(&buf).unwrap();
+        connection
+            .write(&mut serialize(&Hello::new()).unwrap())
+            .unwrap();
+
+        (connection, server)
+    }
+
+    #[test]
+    fn message_size() {
+        let (connection, server) = start_server();
+        // Let the client send more than the server accepts.
+        let mut connection = Framed::new(connection, MAX_MESSAGE * 2);
+        let mut buf = Vec::new();
+
+        let command = ListJobsCommand {
+            name_prefix: Some(OsString::from("x".repeat(0x4000))),
+            states: Vec::new(),
+        };
+        let result = run_command(&mut connection, command, &mut buf).unwrap();
+        assert!(result.unwrap().jobs.is_empty());
+
+        // Too long for the server to accept, it gives up on the connection.
+        let command = ListJobsCommand {
+            name_prefix: Some(OsString::from("x".repeat(MAX_MESSAGE))),
+            states: Vec::new(),
+        };
+        assert!(run_command(&mut connection, command, &mut buf).is_err());
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn garbage_command() {
+        let (connection, server) = start_server();
+        let mut connection = Framed::new(connection, MAX_MESSAGE);
+
+        // An error, not a panic.
+        let mut garbage: Vec<u8> = (0..64).map(|_| rand::random()).collect();
+        garbage[0] = 0xff;
+        connection.write(&mut garbage).unwrap();
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn client_disconnect() {
+        let (connection, server) = start_server();
+
+        // Closing the pipe is a clean shutdown.
+        drop(connection);
+        assert_eq!(server.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn list_jobs() {
+        let root = TempDir::new("server");
+        root.create_file("update.mar", b"0123456789");
+        let backend = SimBackend::new(root.to_path_buf(), None);
+
+        let create = |name: &str, resume: bool| {
+            let mut job = backend.create_job(OsStr::new(name)).unwrap();
+            if resume {
+                let save_path = root.join(format!("{}.mar", name));
+                job.add_file(OsStr::new("update.mar"), save_path.as_os_str())
+                    .unwrap();
+                job.resume().unwrap();
+            }
+            job.guid().unwrap()
+        };
+        let queued = create("alpha-queued", true);
+        let suspended = create("alpha-suspended", false);
+        let other = create("beta", false);
+        let mut cancelled = backend.create_job(OsStr::new("alpha-cancelled")).unwrap();
+        cancelled.cancel().unwrap();
+
+        let list = |name_prefix: Option<&str>, states: Vec<JobState>| {
+            let cmd = ListJobsCommand {
+                name_prefix: name_prefix.map(OsString::from),
+                states,
+            };
+            let mut guids: Vec<Guid> = run_list(&backend, &cmd)
+                .unwrap()
+                .jobs
+                .into_iter()
+                .map(|info| info.guid)
+                .collect();
+            guids.sort_by_key(|guid| guid.to_string());
+            guids
+        };
+        let sorted = |mut guids: Vec<Guid>| {
+            guids.sort_by_key(|guid| guid.to_string());
+            guids
+        };
+
+        assert_eq!(
+            list(None, Vec::new()),
+            sorted(vec![queued.clone(), suspended.clone(), other.clone()])
+        );
+        assert_eq!(
+            list(Some("alpha"), Vec::new()),
+            sorted(vec![queued.clone(), suspended.clone()])
+        );
+        assert_eq!(
+            list(None, vec![JobState::Suspended]),
+            sorted(vec![suspended.clone(), other])
+        );
+        assert_eq!(list(Some("alpha"), vec![JobState::Queued]), vec![queued]);
+        assert!(list(Some("gamma"), Vec::new()).is_empty());
+    }
+
+    fn properties(display_name: &str, description: &str) -> JobProperties {
+        JobProperties {
+            display_name: OsString::from(display_name),
+            description: OsString::from(description),
+            priority: JobPriority::Normal,
+            proxy: ProxySettings::Preconfig,
+        }
+    }
+
+    #[test]
+    fn property_limits() {
+        let valid = |display_name: &str, description: &str| {
+            validate_properties(&properties(display_name, description)).is_ok()
+        };
+
+        assert!(valid("x", ""));
+        assert!(!valid("", ""));
+        assert!(valid(&"x".repeat(MAX_DISPLAY_NAME), ""));
+        assert!(!valid(&"x".repeat(MAX_DISPLAY_NAME + 1), ""));
+        // Counted in UTF-16 units, as BITS does.
+        assert!(valid(&"\u{1F600}".repeat(MAX_DISPLAY_NAME / 2), ""));
+        assert!(!valid(&"\u{1F600}".repeat(MAX_DISPLAY_NAME / 2 + 1), ""));
+        assert!(!valid("x\0y", ""));
+
+        assert!(valid("x", &"y".repeat(MAX_DESCRIPTION)));
+        assert!(!valid("x", &"y".repeat(MAX_DESCRIPTION + 1)));
+        assert!(!valid("x", "y\0"));
+    }
+
+    #[test]
+    fn start_failure_cancels() {
+        let root = TempDir::new("server");
+        root.create_file("update.mar", b"0123456789");
+        let backend = SimBackend::new(root.to_path_buf(), None);
+        let monitors = Monitors::new(backend.clone(), MemoryTransport::new());
+
+        // The sim, like BITS, only takes full paths to save to.
+        let cmd = StartJobCommand {
+            files: vec![
+                FileSpec {
+                    url: OsString::from("update.mar"),
+                    save_path: root.join("saved.mar").into_os_string(),
+                },
+                FileSpec {
+                    url: OsString::from("update.mar"),
+                    save_path: OsString::from("saved.mar"),
+                },
+            ],
+            properties: properties("test", ""),
+            monitor: None,
+        };
+        match run_start(&backend, &monitors, &cmd) {
+            Err(CommandFailure::InvalidArgument(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(backend.list_jobs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_priority() {
+        let (connection, server) = start_server();
+        let mut connection = Framed::new(connection, MAX_MESSAGE);
+
+        // The priority is last, make it one past `Low`.
+        let mut command = serialize(&Command::SetJobPriority(SetJobPriorityCommand {
+            guid: Guid::new_v4(),
+            priority: JobPriority::Low,
+        })).unwrap();
+        let len = command.len();
+        assert_eq!(command[len - 4..], [3, 0, 0, 0]);
+        command[len - 4] = 4;
+        connection.write(&mut command).unwrap();
+        assert!(server.join().unwrap().is_err());
+    }
+
+    fn proxy_override(proxy_list: &str, bypass_list: Option<&str>) -> ProxySettings {
+        ProxySettings::Override {
+            proxy_list: OsString::from(proxy_list),
+            bypass_list: bypass_list.map(OsString::from),
+        }
+    }
+
+    #[test]
+    fn proxy_limits() {
+        let valid = |proxy: ProxySettings| validate_proxy(&proxy).is_ok();
+        let long = "x".repeat(MAX_PROXY_LIST + 1);
+
+        assert!(valid(ProxySettings::Preconfig));
+        assert!(valid(ProxySettings::NoProxy));
+        assert!(valid(proxy_override("proxy:8080", None)));
+        assert!(valid(proxy_override("http=a https=b:443", Some("<local>"))));
+        assert!(valid(proxy_override(&long[1..], Some(&long[1..]))));
+
+        assert!(!valid(proxy_override("", None)));
+        assert!(!valid(proxy_override("", Some("<local>"))));
+        assert!(!valid(proxy_override("proxy\0", None)));
+        assert!(!valid(proxy_override(&long, None)));
+        assert!(!valid(proxy_override("proxy", Some("local\0"))));
+        assert!(!valid(proxy_override("proxy", Some(&long))));
+    }
+
+    #[test]
+    fn set_proxy() {
+        let backend = SimBackend::new(env::temp_dir(), None);
+        let mut job = backend.create_job(OsStr::new("test")).unwrap();
+        let guid = job.guid().unwrap();
+        let set = |guid: &Guid, proxy| {
+            let cmd = SetProxyCommand {
+                guid: guid.clone(),
+                proxy,
+            };
+            run_set_proxy(&backend, &cmd)
+        };
+
+        for proxy in &[
+            ProxySettings::NoProxy,
+            proxy_override("proxy:8080", Some("<local>")),
+            ProxySettings::Preconfig,
+        ] {
+            set(&guid, proxy.clone()).unwrap();
+            assert_eq!(backend.settings(&guid).unwrap().1, *proxy);
+        }
+
+        // Invalid settings leave the old ones.
+        match set(&guid, proxy_override("", None)) {
+            Err(CommandFailure::InvalidArgument(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(backend.settings(&guid).unwrap().1, ProxySettings::Preconfig);
+
+        match set(&Guid::new_v4(), ProxySettings::NoProxy) {
+            Err(CommandFailure::JobNotFound) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        // Gone once cancelled.
+        job.cancel().unwrap();
+        match set(&guid, ProxySettings::NoProxy) {
+            Err(CommandFailure::JobNotFound) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn set_priority() {
+        let backend = SimBackend::new(env::temp_dir(), None);
+        let mut job = backend.create_job(OsStr::new("test")).unwrap();
+        let guid = job.guid().unwrap();
+        let set = |guid: &Guid, priority| {
+            let cmd = SetJobPriorityCommand {
+                guid: guid.clone(),
+                priority,
+            };
+            run_set_priority(&backend, &cmd)
+        };
+
+        for &priority in &[JobPriority::Foreground, JobPriority::Low, JobPriority::High] {
+            set(&guid, priority).unwrap();
+            assert_eq!(backend.settings(&guid).unwrap().0, priority);
+        }
+
+        match set(&Guid::new_v4(), JobPriority::Normal) {
+            Err(CommandFailure::JobNotFound) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        job.cancel().unwrap();
+        match set(&guid, JobPriority::Normal) {
+            Err(CommandFailure::JobNotFound) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn suspend_resume() {
+        let root = TempDir::new("server");
+        root.create_file("update.mar", b"0123456789");
+        let backend = SimBackend::new(root.to_path_buf(), None);
+        let mut job = backend.create_job(OsStr::new("test")).unwrap();
+        let guid = job.guid().unwrap();
+        let suspend =
+            |guid: &Guid| run_suspend(&backend, &SuspendJobCommand { guid: guid.clone() });
+        let resume = |guid: &Guid| run_resume(&backend, &ResumeJobCommand { guid: guid.clone() });
+        let state = || backend.get_job(&guid).unwrap().get_status().unwrap().state;
+
+        // Like BITS, a job without files can't be resumed.
+        match resume(&guid) {
+            Err(CommandFailure::Bits { .. }) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(state(), JobState::Suspended);
+
+        let save_path = root.join("saved.mar");
+        job.add_file(OsStr::new("update.mar"), save_path.as_os_str())
+            .unwrap();
+        resume(&guid).unwrap();
+        assert_eq!(state(), JobState::Queued);
+        resume(&guid).unwrap();
+        assert_eq!(state(), JobState::Queued);
+        suspend(&guid).unwrap();
+        assert_eq!(state(), JobState::Suspended);
+        suspend(&guid).unwrap();
+        assert_eq!(state(), JobState::Suspended);
+        resume(&guid).unwrap();
+        assert_eq!(state(), JobState::Queued);
+
+        let unknown = Guid::new_v4();
+        match suspend(&unknown) {
+            Err(CommandFailure::JobNotFound) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match resume(&unknown) {
+            Err(CommandFailure::JobNotFound) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        job.cancel().unwrap();
+        match suspend(&guid) {
+            Err(CommandFailure::JobNotFound) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match resume(&guid) {
+            Err(CommandFailure::JobNotFound) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    const RESIDENT_TIMEOUT_MS: u64 = 200;
+
+    /// Start a resident server for a first client, which is returned.
+    fn start_resident(
+        backend: &SimBackend,
+        transport: &MemoryTransport,
+        resident_name: &OsStr,
+    ) -> (
+        BitsClient<MemoryTransport>,
+        JoinHandle<result::Result<(), String>>,
+    ) {
+        let listener = transport.duplex_listener().unwrap();
+        let server = {
+            let (backend, transport) = (backend.clone(), transport.clone());
+            let resident_name = resident_name.to_os_string();
+            let name = listener.name().to_os_string();
+            thread::spawn(move || {
+                let idle_timeout = Duration::from_millis(RESIDENT_TIMEOUT_MS);
+                run_resident(
+                    &backend,
+                    &transport,
+                    &resident_name,
+                    &name,
+                    TOKEN,
+                    idle_timeout,
+                )
+            })
+        };
+
+        let connection = listener.accept().unwrap();
+        let client = BitsClient::with_connection(transport.clone(), connection, TOKEN).unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn resident_start_and_monitor() {
+        let root = TempDir::new("server");
+        root.create_file("update.mar", b"0123456789");
+        let backend = SimBackend::new(root.to_path_buf(), None);
+        let transport = MemoryTransport::new();
+        let resident_name = OsString::from("resident");
+        let (first, server) = start_resident(&backend, &transport, &resident_name);
+
+        // A connection that never sends its request doesn't hold up the others.
+        let _silent = transport.open_outbound(&resident_name).unwrap();
+
+        let token = "fedcba9876543210fedcba9876543210";
+        let listener = transport.duplex_listener().unwrap();
+        let request = ConnectRequest {
+            pipe_name: listener.name().to_os_string(),
+            token: token.to_string(),
+        };
+        transport
+            .open_outbound(&resident_name)
+            .unwrap()
+            .write(&mut serialize(&request).unwrap())
+            .unwrap();
+        let connection = listener.accept().unwrap();
+        let mut second = BitsClient::with_connection(transport.clone(), connection, token).unwrap();
+        drop(first);
+
+        let files = vec![FileSpec {
+            url: OsString::from("update.mar"),
+            save_path: root.join("saved.mar").into_os_string(),
+        }];
+        let (guid, monitor) = second.start_job(files, properties("test", "")).unwrap();
+        drop(second);
+
+        // With both clients gone, the monitor keeps the server from going idle.
+        thread::sleep(Duration::from_millis(RESIDENT_TIMEOUT_MS * 2));
+        assert!(backend.step(&guid).unwrap());
+        let messages = monitor.collect::<Result<Vec<_>>>().unwrap();
+        match messages.last() {
+            Some(MonitorMessage::Completed(Ok(result))) => assert!(!result.is_partial()),
+            m => panic!("unexpected {:?}", m),
+        }
+
+        assert_eq!(server.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn resident_idle_timeout() {
+        let backend = SimBackend::new(env::temp_dir(), None);
+        let transport = MemoryTransport::new();
+        let resident_name = OsString::from("resident");
+        let started = Instant::now();
+        let (client, server) = start_resident(&backend, &transport, &resident_name);
+
+        // Another server finds this one resident, and only serves its own client.
+        let (other, other_server) = start_resident(&backend, &transport, &resident_name);
+        drop(other);
+        assert_eq!(other_server.join().unwrap(), Ok(()));
+
+        drop(client);
+        assert_eq!(server.join().unwrap(), Ok(()));
+        assert!(started.elapsed() >= Duration::from_millis(RESIDENT_TIMEOUT_MS));
+
+        // Gone, so the next server can be resident.
+        assert!(transport.inbound_acceptor(&resident_name).is_ok());
+    }
 }
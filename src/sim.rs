@@ -0,0 +1,643 @@
+// A simulated download backend, so the server can be exercised without the BITS service.
+//
+// Remote files are served out of a local directory: a URL like `http://localhost/a/b.mar` (the
+// scheme and host are ignored, standing in for a loopback HTTP server) or just `a/b.mar` is read
+// from `<root>/a/b.mar`. Each job follows a script of steps, which can report progress, hit
+// errors, or finish transferring. Steps run either from a driver thread started by `resume`, or
+// one at a time via `SimBackend::step`.
+
+// Not used outside of tests yet.
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use comical::error::{Error, ErrorCode, Result};
+use comical::guid::Guid;
+
+use backend::{
+    DownloadBackend, DownloadJob, ErrorCallback, ModificationCallback, TransferredCallback,
+};
+use error::{BG_E_NOT_FOUND, E_INVALIDARG};
+use protocol::{
+    BitsFileProgress, BitsJobError, BitsJobStatus, CompleteResult, ErrorContext, HResult,
+    JobPriority, JobProgress, JobState, ProxySettings,
+};
+
+// Other errors BITS would report, defined here so the sim doesn't need the Windows headers.
+const BG_E_INVALID_STATE: i32 = 0x8020_0002u32 as i32;
+const ERROR_FILE_NOT_FOUND: u32 = 2;
+
+fn hresult_from_win32(rc: u32) -> i32 {
+    if rc as i32 <= 0 {
+        rc as i32
+    } else {
+        ((rc & 0xffff) | 0x8007_0000) as i32
+    }
+}
+
+/// One step of a simulated transfer.
+#[derive(Clone, Debug)]
+pub enum SimStep {
+    /// Transfer up to this many more bytes.
+    Progress(u64),
+    /// Hit an error that will be retried, the next step continues transferring.
+    TransientError(ErrorContext, HResult),
+    /// Hit a fatal error, the job stays in the error state until cancelled.
+    Error(ErrorContext, HResult),
+    /// Transfer whatever is left and finish.
+    Transferred,
+}
+
+#[derive(Clone)]
+pub struct SimBackend {
+    shared: Arc<SimShared>,
+}
+
+struct SimShared {
+    root: PathBuf,
+    step_interval: Option<Duration>,
+    script: Mutex<Vec<SimStep>>,
+    jobs: Mutex<Vec<Arc<Mutex<SimJobState>>>>,
+}
+
+impl SimBackend {
+    /// Serve files from `root`. If `step_interval` is given, resumed jobs run their scripts on
+    /// their own, taking one step per interval; otherwise steps only happen via `step`.
+    pub fn new(root: PathBuf, step_interval: Option<Duration>) -> Self {
+        SimBackend {
+            shared: Arc::new(SimShared {
+                root,
+                step_interval,
+                script: Mutex::new(vec![SimStep::Transferred]),
+                jobs: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Set the script that jobs created from now on will follow.
+    pub fn set_script(&self, script: Vec<SimStep>) {
+        *self.shared.script.lock().unwrap() = script;
+    }
+
+    /// The priority and proxy settings a job has been given.
+    pub fn settings(&self, guid: &Guid) -> Result<(JobPriority, ProxySettings)> {
+        let job = self.get_job(guid)?;
+        let state = job.state.lock().unwrap();
+        Ok((state.priority, state.proxy.clone()))
+    }
+
+    /// Run the next step of a job's script, returns false if there was nothing to do.
+    pub fn step(&self, guid: &Guid) -> Result<bool> {
+        Ok(self.get_job(guid)?.step())
+    }
+
+    fn source_path(&self, remote_url: &OsStr) -> PathBuf {
+        let url = remote_url.to_string_lossy();
+        // Drop the scheme and host, if any.
+        let path = match url.find("://") {
+            Some(i) => match url[i + 3..].find('/') {
+                Some(j) => url[i + 3 + j..].to_string(),
+                None => String::new(),
+            },
+            None => url.to_string(),
+        };
+
+        let mut source = self.shared.root.clone();
+        for component in path.split(|c| c == '/' || c == '\\') {
+            if !component.is_empty() && component != "." && component != ".." {
+                source.push(component);
+            }
+        }
+        source
+    }
+}
+
+impl DownloadBackend for SimBackend {
+    type Job = SimJob;
+    type ThreadGuard = ();
+
+    fn init_thread(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn create_job(&self, display_name: &OsStr) -> Result<SimJob> {
+        let state = Arc::new(Mutex::new(SimJobState {
+            guid: Guid::new_v4(),
+            display_name: display_name.to_os_string(),
+            description: OsString::new(),
+            priority: JobPriority::Normal,
+            proxy: ProxySettings::Preconfig,
+            files: Vec::new(),
+            state: JobState::Suspended,
+            progress: JobProgress::default(),
+            error_count: 0,
+            error: None,
+            script: self.shared.script.lock().unwrap().iter().cloned().collect(),
+            callbacks: None,
+            driver_running: false,
+        }));
+
+        self.shared.jobs.lock().unwrap().push(state.clone());
+
+        Ok(SimJob {
+            backend: self.clone(),
+            state,
+        })
+    }
+
+    fn get_job(&self, guid: &Guid) -> Result<SimJob> {
+        // As with BITS, jobs are gone once they have been completed or cancelled.
+        let jobs = self.shared.jobs.lock().unwrap();
+        let found = jobs.iter().find(|job| {
+            let job = job.lock().unwrap();
+            job.guid == *guid && !job.is_final()
+        });
+        match found {
+            Some(state) => Ok(SimJob {
+                backend: self.clone(),
+                state: state.clone(),
+            }),
+            None => Err(sim_error("IBackgroundCopyManager::GetJob", BG_E_NOT_FOUND)),
+        }
+    }
+
+    fn list_jobs(&self) -> Result<Vec<SimJob>> {
+        Ok(self
+            .shared
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|job| !job.lock().unwrap().is_final())
+            .map(|state| SimJob {
+                backend: self.clone(),
+                state: state.clone(),
+            })
+            .collect())
+    }
+}
+
+struct SimFile {
+    remote_url: OsString,
+    local_file: OsString,
+    size: Option<u64>,
+}
+
+struct SimCallbacks {
+    transferred: Option<Box<TransferredCallback<SimJob>>>,
+    error: Option<Box<ErrorCallback<SimJob>>>,
+    modification: Option<Box<ModificationCallback<SimJob>>>,
+}
+
+struct SimJobState {
+    guid: Guid,
+    display_name: OsString,
+    description: OsString,
+    priority: JobPriority,
+    proxy: ProxySettings,
+    files: Vec<SimFile>,
+    state: JobState,
+    progress: JobProgress,
+    error_count: u32,
+    error: Option<BitsJobError>,
+    script: VecDeque<SimStep>,
+    callbacks: Option<Arc<SimCallbacks>>,
+    driver_running: bool,
+}
+
+impl SimJobState {
+    fn is_running(&self) -> bool {
+        self.state == JobState::Queued
+            || self.state == JobState::Transferring
+            || self.state == JobState::TransientError
+    }
+
+    fn is_final(&self) -> bool {
+        self.state == JobState::Acknowledged || self.state == JobState::Cancelled
+    }
+
+    fn set_error(&mut self, state: JobState, context: ErrorContext, error: HResult) {
+        self.state = state;
+        self.error_count += 1;
+        self.error = Some(BitsJobError { context, error });
+    }
+
+    // Files are transferred one after another, so divide the total bytes transferred among them.
+    fn file_progress(&self) -> Vec<BitsFileProgress> {
+        let mut remaining = self.progress.bytes_transferred;
+        self.files
+            .iter()
+            .map(|file| {
+                let size = file.size.unwrap_or(0);
+                let transferred = remaining.min(size);
+                remaining -= transferred;
+                BitsFileProgress {
+                    bytes_total: size,
+                    bytes_transferred: transferred,
+                    completed: file.size.is_some() && transferred == size,
+                }
+            })
+            .collect()
+    }
+
+    fn update_files_transferred(&mut self) {
+        self.progress.files_transferred = self
+            .file_progress()
+            .iter()
+            .filter(|file| file.completed)
+            .count() as u32;
+    }
+}
+
+#[derive(Clone)]
+pub struct SimJob {
+    backend: SimBackend,
+    state: Arc<Mutex<SimJobState>>,
+}
+
+enum SimEvent {
+    Transferred,
+    Error(BitsJobError),
+    Modification,
+}
+
+impl SimJob {
+    /// Run the next step of the script, returns false if the job isn't running or the script
+    /// is finished.
+    fn step(&self) -> bool {
+        let (events, callbacks) = {
+            let mut state = self.state.lock().unwrap();
+            if !state.is_running() {
+                return false;
+            }
+            let step = match state.script.pop_front() {
+                Some(step) => step,
+                None => return false,
+            };
+
+            let mut events = vec![SimEvent::Modification];
+            match step {
+                SimStep::Progress(bytes) => {
+                    state.state = JobState::Transferring;
+                    state.error = None;
+                    let total = state.progress.bytes_total;
+                    state.progress.bytes_transferred =
+                        total.min(state.progress.bytes_transferred + bytes);
+                    state.update_files_transferred();
+                }
+                SimStep::TransientError(context, error) => {
+                    state.set_error(JobState::TransientError, context, error);
+                }
+                SimStep::Error(context, error) => {
+                    state.set_error(JobState::Error, context, error);
+                    events.push(SimEvent::Error(state.error.clone().unwrap()));
+                }
+                SimStep::Transferred => {
+                    state.state = JobState::Transferred;
+                    state.error = None;
+                    state.progress.bytes_transferred = state.progress.bytes_total;
+                    state.progress.files_transferred = state.progress.files_total;
+                    events.push(SimEvent::Transferred);
+                }
+            }
+
+            (events, state.callbacks.clone())
+        };
+
+        self.notify(events, callbacks);
+        true
+    }
+
+    /// Callbacks must be run without the lock held, as they usually call back into the job.
+    fn notify(&self, events: Vec<SimEvent>, callbacks: Option<Arc<SimCallbacks>>) {
+        if let Some(callbacks) = callbacks {
+            for event in events {
+                match event {
+                    SimEvent::Transferred => if let Some(ref cb) = callbacks.transferred {
+                        cb(self.clone());
+                    },
+                    SimEvent::Error(error) => if let Some(ref cb) = callbacks.error {
+                        cb(self.clone(), error);
+                    },
+                    SimEvent::Modification => if let Some(ref cb) = callbacks.modification {
+                        cb(self.clone());
+                    },
+                }
+            }
+        }
+    }
+
+    fn start_driver(&self, interval: Duration) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.driver_running {
+                return;
+            }
+            state.driver_running = true;
+        }
+
+        let job = self.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                if !job.step() {
+                    break;
+                }
+            }
+            job.state.lock().unwrap().driver_running = false;
+        });
+    }
+}
+
+impl DownloadJob for SimJob {
+    fn guid(&self) -> Result<Guid> {
+        Ok(self.state.lock().unwrap().guid.clone())
+    }
+
+    fn display_name(&self) -> Result<OsString> {
+        Ok(self.state.lock().unwrap().display_name.clone())
+    }
+
+    fn set_description(&mut self, description: &OsStr) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_final() {
+            return Err(sim_error(
+                "IBackgroundCopyJob::SetDescription",
+                BG_E_INVALID_STATE,
+            ));
+        }
+
+        state.description = description.to_os_string();
+        Ok(())
+    }
+
+    fn set_priority(&mut self, priority: JobPriority) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_final() {
+            return Err(sim_error(
+                "IBackgroundCopyJob::SetPriority",
+                BG_E_INVALID_STATE,
+            ));
+        }
+
+        state.priority = priority;
+        Ok(())
+    }
+
+    fn set_proxy_settings(&mut self, proxy: &ProxySettings) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let ProxySettings::Override { proxy_list, .. } = proxy {
+            if proxy_list.is_empty() {
+                return Err(sim_error(
+                    "IBackgroundCopyJob::SetProxySettings",
+                    E_INVALIDARG,
+                ));
+            }
+        }
+        if state.is_final() {
+            return Err(sim_error(
+                "IBackgroundCopyJob::SetProxySettings",
+                BG_E_INVALID_STATE,
+            ));
+        }
+
+        state.proxy = proxy.clone();
+        Ok(())
+    }
+
+    fn add_file(&mut self, remote_url: &OsStr, local_file: &OsStr) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_final() || state.state == JobState::Transferred {
+            return Err(sim_error("IBackgroundCopyJob::AddFile", BG_E_INVALID_STATE));
+        }
+        if !Path::new(local_file).is_absolute() {
+            return Err(sim_error("IBackgroundCopyJob::AddFile", E_INVALIDARG));
+        }
+
+        let size = fs::metadata(self.backend.source_path(remote_url))
+            .ok()
+            .map(|m| m.len());
+        state.files.push(SimFile {
+            remote_url: remote_url.to_os_string(),
+            local_file: local_file.to_os_string(),
+            size,
+        });
+        state.progress.files_total += 1;
+        state.progress.bytes_total += size.unwrap_or(0);
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        let callbacks = {
+            let mut state = self.state.lock().unwrap();
+            if state.is_final() || state.files.is_empty() {
+                return Err(sim_error("IBackgroundCopyJob::Resume", BG_E_INVALID_STATE));
+            }
+            if state.state != JobState::Suspended && state.state != JobState::Error {
+                return Ok(());
+            }
+
+            if state.files.iter().any(|file| file.size.is_none()) {
+                // Fail right away, like a 404 would.
+                state.set_error(
+                    JobState::Error,
+                    ErrorContext::RemoteFile,
+                    HResult(hresult_from_win32(ERROR_FILE_NOT_FOUND)),
+                );
+                let events = vec![
+                    SimEvent::Modification,
+                    SimEvent::Error(state.error.clone().unwrap()),
+                ];
+                let callbacks = state.callbacks.clone();
+                drop(state);
+                self.notify(events, callbacks);
+                return Ok(());
+            }
+
+            state.state = JobState::Queued;
+            state.error = None;
+            state.callbacks.clone()
+        };
+
+        self.notify(vec![SimEvent::Modification], callbacks);
+        if let Some(interval) = self.backend.shared.step_interval {
+            self.start_driver(interval);
+        }
+        Ok(())
+    }
+
+    fn suspend(&mut self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_final() {
+            return Err(sim_error("IBackgroundCopyJob::Suspend", BG_E_INVALID_STATE));
+        }
+        if state.is_running() {
+            state.state = JobState::Suspended;
+            let callbacks = state.callbacks.clone();
+            drop(state);
+            self.notify(vec![SimEvent::Modification], callbacks);
+        }
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Result<CompleteResult> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_final() {
+            return Err(sim_error(
+                "IBackgroundCopyJob::Complete",
+                BG_E_INVALID_STATE,
+            ));
+        }
+
+        // As with BITS, completing early only commits the files that finished transferring.
+        let committed: Vec<bool> = state.file_progress().iter().map(|f| f.completed).collect();
+        for (file, _) in state.files.iter().zip(&committed).filter(|(_, c)| **c) {
+            fs::copy(self.backend.source_path(&file.remote_url), &file.local_file).map_err(
+                |e| {
+                    Error::Message(format!(
+                        "copying {} failed: {}",
+                        file.remote_url.to_string_lossy(),
+                        e
+                    ))
+                },
+            )?;
+        }
+        state.state = JobState::Acknowledged;
+        Ok(CompleteResult {
+            committed,
+            unable_to_delete_temp_files: false,
+        })
+    }
+
+    fn cancel(&mut self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_final() {
+            return Err(sim_error("IBackgroundCopyJob::Cancel", BG_E_INVALID_STATE));
+        }
+        state.state = JobState::Cancelled;
+        Ok(())
+    }
+
+    fn get_status(&mut self) -> Result<BitsJobStatus> {
+        let state = self.state.lock().unwrap();
+        Ok(BitsJobStatus {
+            state: state.state,
+            progress: state.progress.clone(),
+            files: state.file_progress(),
+            error_count: state.error_count,
+            error: state.error.clone(),
+        })
+    }
+
+    fn register_callbacks(
+        &mut self,
+        transferred: Option<Box<TransferredCallback<SimJob>>>,
+        error: Option<Box<ErrorCallback<SimJob>>>,
+        modification: Option<Box<ModificationCallback<SimJob>>>,
+    ) -> Result<()> {
+        self.state.lock().unwrap().callbacks = Some(Arc::new(SimCallbacks {
+            transferred,
+            error,
+            modification,
+        }));
+        Ok(())
+    }
+}
+
+fn sim_error(api: &'static str, hr: i32) -> Error {
+    Error::Api(api, ErrorCode::HResult(hr), None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use temp_dir::TempDir;
+
+    #[test]
+    fn scripted_transfer() {
+        let root = TempDir::new("sim");
+        root.create_file("update.mar", b"0123456789");
+        root.create_file("partial.mar", b"abc");
+
+        let backend = SimBackend::new(root.to_path_buf(), None);
+        backend.set_script(vec![
+            SimStep::Progress(4),
+            SimStep::TransientError(ErrorContext::RemoteFile, HResult(hresult_from_win32(5))),
+            SimStep::Transferred,
+        ]);
+
+        let save_path = root.join("saved.mar");
+        let mut job = backend.create_job(OsStr::new("test")).unwrap();
+        let guid = job.guid().unwrap();
+        let partial_save_path = root.join("saved-partial.mar");
+        job.add_file(
+            OsStr::new("http://localhost/partial.mar"),
+            partial_save_path.as_os_str(),
+        ).unwrap();
+        job.add_file(
+            OsStr::new("http://localhost/update.mar"),
+            save_path.as_os_str(),
+        ).unwrap();
+        job.resume().unwrap();
+
+        assert!(backend.step(&guid).unwrap());
+        let status = job.get_status().unwrap();
+        assert_eq!(status.state, JobState::Transferring);
+        assert_eq!(status.progress.bytes_total, 13);
+        assert_eq!(status.progress.bytes_transferred, 4);
+        assert_eq!(status.progress.files_transferred, 1);
+        assert!(status.files[0].completed);
+        assert!(!status.files[1].completed);
+        assert_eq!(status.files[1].bytes_transferred, 1);
+
+        assert!(backend.step(&guid).unwrap());
+        let status = job.get_status().unwrap();
+        assert_eq!(status.state, JobState::TransientError);
+        assert_eq!(status.error_count, 1);
+
+        assert!(backend.step(&guid).unwrap());
+        assert_eq!(job.get_status().unwrap().state, JobState::Transferred);
+        assert!(!backend.step(&guid).unwrap());
+
+        let result = job.complete().unwrap();
+        assert_eq!(result.committed, vec![true, true]);
+        assert!(!result.is_partial());
+        assert!(job.complete().is_err());
+        assert_eq!(fs::read_to_string(&save_path).unwrap(), "0123456789");
+        assert_eq!(fs::metadata(&partial_save_path).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn partial_completion() {
+        let root = TempDir::new("sim");
+        root.create_file("a.mar", b"abc");
+        root.create_file("b.mar", b"defg");
+
+        let backend = SimBackend::new(root.to_path_buf(), None);
+        backend.set_script(vec![SimStep::Progress(5)]);
+
+        let mut job = backend.create_job(OsStr::new("test")).unwrap();
+        let guid = job.guid().unwrap();
+        let (a_save, b_save) = (root.join("saved-a.mar"), root.join("saved-b.mar"));
+        job.add_file(OsStr::new("a.mar"), a_save.as_os_str())
+            .unwrap();
+        job.add_file(OsStr::new("b.mar"), b_save.as_os_str())
+            .unwrap();
+        job.resume().unwrap();
+        assert!(backend.step(&guid).unwrap());
+
+        let result = job.complete().unwrap();
+        assert_eq!(result.committed, vec![true, false]);
+        assert!(result.is_partial());
+        assert!(a_save.exists());
+        assert!(!b_save.exists());
+        assert_eq!(job.get_status().unwrap().state, JobState::Acknowledged);
+    }
+}
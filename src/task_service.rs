@@ -1,27 +1,39 @@
 use std::ffi::OsStr;
+use std::mem;
+use std::ptr;
 
 use comical::bstr::BStr;
 use comical::com::{cast, create_instance_inproc_server, getter};
 use comical::error::{
     check_hresult, check_nonzero, Error, ErrorCode, LabelErrorDWord, LabelErrorHResult, Result,
 };
+use comical::handle::Handle;
 use comical::safearray::SafeArray;
-use comical::variant::{Variant, VariantValue, VARIANT_FALSE, VARIANT_TRUE};
-use comical::{call, get};
+use comical::variant::{Variant, VARIANT_FALSE, VARIANT_TRUE};
+use comical::{call, check_api_nonzero, get};
 
-use winapi::shared::minwindef::{DWORD, MAX_PATH};
+use winapi::shared::minwindef::{DWORD, FALSE, MAX_PATH};
 use winapi::shared::ntdef::LONG;
-use winapi::shared::winerror::{ERROR_FILE_NOT_FOUND, HRESULT_FROM_WIN32};
-use winapi::um::processthreadsapi::GetCurrentProcess;
+use winapi::shared::winerror::{
+    ERROR_FILE_NOT_FOUND, HRESULT_FROM_WIN32, SCHED_E_TASK_NOT_RUNNING,
+};
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcess, OpenProcessToken};
+use winapi::um::securitybaseapi::{GetTokenInformation, IsWellKnownSid};
 use winapi::um::taskschd::{
     IActionCollection, IExecAction, IIdleSettings, IRegisteredTask, IRegistrationInfo,
-    ITaskDefinition, ITaskFolder, ITaskService, ITaskSettings, TaskScheduler, TASK_ACTION_EXEC,
-    TASK_CREATE_OR_UPDATE, TASK_DONT_ADD_PRINCIPAL_ACE, TASK_INSTANCES_PARALLEL,
-    TASK_LOGON_SERVICE_ACCOUNT,
+    IRunningTask, ITaskDefinition, ITaskFolder, ITaskService, ITaskSettings, TaskScheduler,
+    TASK_ACTION_EXEC, TASK_CREATE_OR_UPDATE, TASK_DONT_ADD_PRINCIPAL_ACE, TASK_INSTANCES_PARALLEL,
+    TASK_LOGON_SERVICE_ACCOUNT, TASK_STATE_QUEUED, TASK_STATE_RUNNING,
 };
 use winapi::um::winbase::QueryFullProcessImageNameW;
+use winapi::um::winnt::{
+    TokenUser, WinLocalServiceSid, PROCESS_QUERY_LIMITED_INFORMATION, TOKEN_QUERY, TOKEN_USER,
+};
 use wio::com::ComPtr;
 
+use logging::LogContext;
+
 fn connect_task_service() -> Result<(ComPtr<ITaskService>, ComPtr<ITaskFolder>)> {
     let task_service = create_instance_inproc_server::<TaskScheduler, ITaskService>()?;
 
@@ -147,6 +159,7 @@ pub fn install(task_name: &OsStr, command_line: &OsStr) -> Result<()> {
                 task_name.get(),
                 task_def.as_raw(),
                 TASK_CREATE_OR_UPDATE as LONG,
+                // Keep `runs_as_task_account` in sync.
                 Variant::<BStr>::wrap(&mut BStr::from("NT AUTHORITY\\LocalService")).get(),
                 Variant::null().get(), // password
                 TASK_LOGON_SERVICE_ACCOUNT,
@@ -185,7 +198,88 @@ pub fn uninstall(task_name: &OsStr) -> Result<()> {
     Ok(())
 }
 
-pub fn run_on_demand(task_name: &OsStr, args: &[&OsStr]) -> Result<()> {
+/// Whether process `pid` runs as the account that `install` sets tasks up to run as. Only the
+/// task can run as that account, so it can be trusted with a client's token.
+///
+/// Fails if the process can't be queried, which may be the case for users without admin rights.
+pub fn runs_as_task_account(pid: u32) -> Result<bool> {
+    let process = unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid);
+        if process.is_null() {
+            Err(GetLastError())
+        } else {
+            Handle::wrap(process)
+        }
+    }.map_api_rc("OpenProcess")?;
+
+    let mut token = ptr::null_mut();
+    unsafe { check_api_nonzero!(OpenProcessToken(*process, TOKEN_QUERY, &mut token)) }?;
+    let token = unsafe { Handle::wrap(token) }.map_api_rc("OpenProcessToken")?;
+
+    // A `TOKEN_USER` followed by the SID it points to, which is at most 68 bytes.
+    let mut buf = [0u64; 16];
+    let mut len = 0;
+    unsafe {
+        check_api_nonzero!(GetTokenInformation(
+            *token,
+            TokenUser,
+            buf.as_mut_ptr() as *mut _,
+            mem::size_of_val(&buf) as DWORD,
+            &mut len,
+        ))
+    }?;
+    let user = unsafe { &*(buf.as_ptr() as *const TOKEN_USER) };
+    Ok(unsafe { IsWellKnownSid(user.User.Sid, WinLocalServiceSid) } != FALSE)
+}
+
+/// An instance of a task, started by `run_on_demand`.
+pub struct RunningTask {
+    task: ComPtr<IRunningTask>,
+}
+
+impl RunningTask {
+    /// Update the cached state, false if the instance is gone.
+    fn refresh(&self) -> Result<bool> {
+        match unsafe { call!(self.task, IRunningTask::Refresh()) } {
+            Ok(_) => Ok(true),
+            Err(Error::Api(_, ErrorCode::HResult(hr), _)) if hr == SCHED_E_TASK_NOT_RUNNING => {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the instance is still queued or running, false once it has exited.
+    pub fn is_running(&self) -> Result<bool> {
+        if !self.refresh()? {
+            return Ok(false);
+        }
+
+        let mut state = 0;
+        unsafe { call!(self.task, IRunningTask::get_State(&mut state)) }?;
+        Ok(state == TASK_STATE_QUEUED || state == TASK_STATE_RUNNING)
+    }
+
+    /// The process running the instance, `None` if it hasn't started or is gone.
+    pub fn pid(&self) -> Result<Option<u32>> {
+        if !self.refresh()? {
+            return Ok(None);
+        }
+
+        let mut pid = 0;
+        unsafe { call!(self.task, IRunningTask::get_EnginePID(&mut pid)) }?;
+        Ok(if pid == 0 { None } else { Some(pid) })
+    }
+}
+
+pub fn run_on_demand(task_name: &OsStr, args: &[&OsStr]) -> Result<RunningTask> {
+    // Not the arguments themselves, they include the token.
+    log_debug!(
+        LogContext::default(),
+        "running {} with {} arguments",
+        task_name.to_string_lossy(),
+        args.len()
+    );
     let task_name = BStr::from(task_name);
 
     let args = args.into_iter().map(|a| BStr::from(*a)).collect::<Vec<_>>();
@@ -196,16 +290,10 @@ pub fn run_on_demand(task_name: &OsStr, args: &[&OsStr]) -> Result<()> {
     }
     let task = maybe_task.unwrap();
 
-    // DEBUG
     let mut sa = SafeArray::try_from(args)?;
     let v = Variant::<SafeArray<_>>::wrap(&mut sa);
-    if let VariantValue::StringVector(Ok(v)) = v.value() {
-        for (i, s) in v.iter().enumerate() {
-            println!("{}: {}", i, s);
-        }
-    }
 
-    unsafe { get!(|rt| task, IRegisteredTask::Run(v.get(), rt))? };
+    let running = unsafe { get!(|rt| task, IRegisteredTask::Run(v.get(), rt))? };
 
-    Ok(())
+    Ok(RunningTask { task: running })
 }
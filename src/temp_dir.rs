@@ -0,0 +1,45 @@
+// A scratch directory for tests, which is removed when dropped, so also when a test fails.
+
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use rand;
+
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create a new empty directory, `prefix` says which tests it's for.
+    pub fn new(prefix: &str) -> Self {
+        let path = env::temp_dir().join(format!(
+            "bitstask-{}-{:032x}",
+            prefix,
+            rand::random::<u128>()
+        ));
+        fs::create_dir(&path).unwrap();
+        TempDir(path)
+    }
+
+    /// Create a file in the directory, returns its path.
+    pub fn create_file(&self, name: &str, contents: &[u8]) -> PathBuf {
+        let path = self.0.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // Don't panic again if a failed test is unwinding.
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::panic::RefUnwindSafe;
+use std::process;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use comical::error::{Error, Result};
+
+use protocol::MAX_MESSAGE;
+
+// Message-oriented connections between the client and the task server. Each `write` on one end
+// is delivered by a single `read` on the other end, with message boundaries preserved.
+//
+// As with the named pipes, the client creates a listener and passes its name to the server,
+// which then opens it. A resident server also accepts requests to do so under a fixed name, see
+// `Acceptor`.
+//
+// Once the other end has closed the connection, reads and writes fail with `Error::Disconnected`.
+
+/// The longest message `read_all` accepts. Messages are refused before anything is allocated for
+/// them, so that a peer can't make us allocate whatever it likes before it has been checked. No
+/// message, framed or not, should be longer.
+pub const MAX_READ_ALL: usize = MAX_MESSAGE;
+
+pub trait MessageRead {
+    /// Read a message into `out_buf`, fails if it doesn't fit.
+    fn read<'b>(&mut self, out_buf: &'b mut [u8]) -> Result<&'b mut [u8]>;
+
+    /// Read a message of up to `MAX_READ_ALL` bytes, replacing the contents of `out`. A longer
+    /// message fails with `Error::MessageTooLong`, after which the connection should be dropped.
+    fn read_all(&mut self, out: &mut Vec<u8>) -> Result<()>;
+}
+
+pub trait MessageWrite {
+    fn write(&mut self, in_buf: &mut [u8]) -> Result<()>;
+}
+
+pub trait Transact {
+    /// Write a message and read the response, of any length, replacing the contents of `out`.
+    fn transact(&mut self, in_buf: &mut [u8], out: &mut Vec<u8>) -> Result<()>;
+}
+
+pub trait Listener {
+    type Connection;
+
+    /// The name the other end should open.
+    fn name(&self) -> &OsStr;
+
+    /// Wait up to `timeout` for the other end to open the listener, returns whether it has.
+    /// Once it has, `accept` returns right away.
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<bool>;
+
+    /// Wait for the other end to open the listener. Only one connection is accepted.
+    fn accept(self) -> Result<Self::Connection>;
+}
+
+/// Identifies the process at the other end of a connection.
+pub trait PeerProcess {
+    /// The other end's process ID, or `None` if the transport can't tell.
+    fn peer_pid(&self) -> Result<Option<u32>>;
+}
+
+/// Accepts any number of connections under a fixed name, for a long-lived server.
+pub trait Acceptor {
+    type Connection;
+
+    /// Wait for the next connection.
+    fn accept(&mut self) -> Result<Self::Connection>;
+}
+
+pub trait Transport: Clone + RefUnwindSafe + Send + 'static {
+    type DuplexListener: Listener<Connection = Self::DuplexConnection>;
+    type DuplexConnection: Transact + MessageRead + MessageWrite + PeerProcess;
+    type InboundListener: Listener<Connection = Self::InboundConnection>;
+    type InboundConnection: MessageRead + Send + 'static;
+    type InboundAcceptor: Acceptor<Connection = Self::InboundConnection>;
+    type DuplexClient: MessageRead + MessageWrite;
+    type OutboundClient: MessageWrite + PeerProcess;
+
+    /// Listen for a connection that will be used for request/response exchanges.
+    fn duplex_listener(&self) -> Result<Self::DuplexListener>;
+
+    /// Listen for a connection that will only be read from.
+    fn inbound_listener(&self) -> Result<Self::InboundListener>;
+
+    /// Listen for any number of connections under `name`, which will only be read from. Fails
+    /// if something is already listening under that name.
+    fn inbound_acceptor(&self, name: &OsStr) -> Result<Self::InboundAcceptor>;
+
+    fn open_duplex(&self, name: &OsStr) -> Result<Self::DuplexClient>;
+
+    fn open_outbound(&self, name: &OsStr) -> Result<Self::OutboundClient>;
+}
+
+/// An in-process transport, for tests.
+///
+/// Listeners are only visible to clones of the `MemoryTransport` that created them.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    listeners: Arc<Mutex<HashMap<OsString, MemoryRegistration>>>,
+}
+
+struct MemoryRegistration {
+    incoming: Sender<MemoryConnection>,
+    /// Stop listening after the first connection, as a `Listener` does.
+    once: bool,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn listener(&self) -> Result<MemoryListener> {
+        let name = OsString::from(format!("{:032x}", rand::random::<u128>()));
+        let incoming = self.register(&name, true)?;
+
+        Ok(MemoryListener {
+            name,
+            incoming,
+            ready: None,
+            transport: self.clone(),
+        })
+    }
+
+    fn register(&self, name: &OsStr, once: bool) -> Result<Receiver<MemoryConnection>> {
+        let (tx, rx) = channel();
+
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.contains_key(name) {
+            return Err(Error::Message("listener name collision".to_string()));
+        }
+        listeners.insert(
+            name.to_os_string(),
+            MemoryRegistration { incoming: tx, once },
+        );
+
+        Ok(rx)
+    }
+
+    fn open(&self, name: &OsStr) -> Result<MemoryConnection> {
+        let incoming = {
+            let mut listeners = self.listeners.lock().unwrap();
+            let once = match listeners.get(name) {
+                Some(registration) => registration.once,
+                None => {
+                    return Err(Error::Message(format!(
+                        "no listener named {}",
+                        name.to_string_lossy()
+                    )))
+                }
+            };
+            if once {
+                // Only one connection is allowed, so stop listening.
+                listeners.remove(name).unwrap().incoming
+            } else {
+                listeners[name].incoming.clone()
+            }
+        };
+
+        let (ours, theirs) = MemoryConnection::pair();
+        incoming
+            .send(theirs)
+            .map_err(|_| Error::Message("listener closed".to_string()))?;
+        Ok(ours)
+    }
+}
+
+impl Transport for MemoryTransport {
+    type DuplexListener = MemoryListener;
+    type DuplexConnection = MemoryConnection;
+    type InboundListener = MemoryListener;
+    type InboundConnection = MemoryConnection;
+    type InboundAcceptor = MemoryAcceptor;
+    type DuplexClient = MemoryConnection;
+    type OutboundClient = MemoryConnection;
+
+    fn duplex_listener(&self) -> Result<MemoryListener> {
+        self.listener()
+    }
+
+    fn inbound_listener(&self) -> Result<MemoryListener> {
+        self.listener()
+    }
+
+    fn inbound_acceptor(&self, name: &OsStr) -> Result<MemoryAcceptor> {
+        let incoming = self.register(name, false)?;
+
+        Ok(MemoryAcceptor {
+            name: name.to_os_string(),
+            incoming,
+            transport: self.clone(),
+        })
+    }
+
+    fn open_duplex(&self, name: &OsStr) -> Result<MemoryConnection> {
+        self.open(name)
+    }
+
+    fn open_outbound(&self, name: &OsStr) -> Result<MemoryConnection> {
+        self.open(name)
+    }
+}
+
+pub struct MemoryListener {
+    name: OsString,
+    incoming: Receiver<MemoryConnection>,
+    /// Received by `wait_timeout`, for `accept` to return.
+    ready: Option<MemoryConnection>,
+    transport: MemoryTransport,
+}
+
+impl Listener for MemoryListener {
+    type Connection = MemoryConnection;
+
+    fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<bool> {
+        if self.ready.is_none() {
+            match self.incoming.recv_timeout(timeout) {
+                Ok(connection) => self.ready = Some(connection),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::Message("transport dropped".to_string()))
+                }
+            }
+        }
+        Ok(self.ready.is_some())
+    }
+
+    fn accept(mut self) -> Result<MemoryConnection> {
+        if let Some(connection) = self.ready.take() {
+            return Ok(connection);
+        }
+        self.incoming
+            .recv()
+            .map_err(|_| Error::Message("transport dropped".to_string()))
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        self.transport.listeners.lock().unwrap().remove(&self.name);
+    }
+}
+
+pub struct MemoryAcceptor {
+    name: OsString,
+    incoming: Receiver<MemoryConnection>,
+    transport: MemoryTransport,
+}
+
+impl Acceptor for MemoryAcceptor {
+    type Connection = MemoryConnection;
+
+    fn accept(&mut self) -> Result<MemoryConnection> {
+        self.incoming
+            .recv()
+            .map_err(|_| Error::Message("transport dropped".to_string()))
+    }
+}
+
+impl Drop for MemoryAcceptor {
+    fn drop(&mut self) {
+        self.transport.listeners.lock().unwrap().remove(&self.name);
+    }
+}
+
+/// One end of an in-process connection.
+pub struct MemoryConnection {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl MemoryConnection {
+    /// Create both ends of a connection.
+    pub fn pair() -> (MemoryConnection, MemoryConnection) {
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        (
+            MemoryConnection { tx: tx1, rx: rx2 },
+            MemoryConnection { tx: tx2, rx: rx1 },
+        )
+    }
+}
+
+impl MessageRead for MemoryConnection {
+    fn read<'b>(&mut self, out_buf: &'b mut [u8]) -> Result<&'b mut [u8]> {
+        let message = self.rx.recv().map_err(|_| Error::Disconnected)?;
+        if message.len() > out_buf.len() {
+            return Err(Error::Message(format!(
+                "message of {} bytes doesn't fit in {} byte buffer",
+                message.len(),
+                out_buf.len()
+            )));
+        }
+
+        let out_buf = &mut out_buf[..message.len()];
+        out_buf.copy_from_slice(&message);
+        Ok(out_buf)
+    }
+
+    fn read_all(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        let message = self.rx.recv().map_err(|_| Error::Disconnected)?;
+        if message.len() > MAX_READ_ALL {
+            return Err(Error::MessageTooLong {
+                len: message.len(),
+                max: MAX_READ_ALL,
+            });
+        }
+
+        *out = message;
+        Ok(())
+    }
+}
+
+impl MessageWrite for MemoryConnection {
+    fn write(&mut self, in_buf: &mut [u8]) -> Result<()> {
+        self.tx
+            .send(in_buf.to_vec())
+            .map_err(|_| Error::Disconnected)
+    }
+}
+
+impl Transact for MemoryConnection {
+    fn transact(&mut self, in_buf: &mut [u8], out: &mut Vec<u8>) -> Result<()> {
+        self.write(in_buf)?;
+        self.read_all(out)
+    }
+}
+
+impl PeerProcess for MemoryConnection {
+    /// Both ends are in this process.
+    fn peer_pid(&self) -> Result<Option<u32>> {
+        Ok(Some(process::id()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn memory_transport() {
+        let transport = MemoryTransport::new();
+        let listener = transport.duplex_listener().unwrap();
+        let name = listener.name().to_os_string();
+
+        let server_transport = transport.clone();
+        let server_name = name.clone();
+        let server = thread::spawn(move || {
+            let mut pipe = server_transport.open_duplex(&server_name).unwrap();
+            let mut buf = [0; 16];
+            let request = pipe.read(&mut buf).unwrap().to_vec();
+            assert_eq!(request, b"ping");
+            pipe.write(&mut b"pong".to_vec()).unwrap();
+        });
+
+        let mut connection = listener.accept().unwrap();
+        let mut response = Vec::new();
+        connection
+            .transact(&mut b"ping".to_vec(), &mut response)
+            .unwrap();
+        assert_eq!(response, b"pong");
+        server.join().unwrap();
+
+        // Only one connection per listener.
+        assert!(transport.open_duplex(&name).is_err());
+    }
+
+    #[test]
+    fn memory_wait_timeout() {
+        let transport = MemoryTransport::new();
+        let mut listener = transport.duplex_listener().unwrap();
+        assert!(!listener.wait_timeout(Duration::from_millis(10)).unwrap());
+
+        let mut client = transport.open_duplex(listener.name()).unwrap();
+        assert!(listener.wait_timeout(Duration::from_millis(10)).unwrap());
+        assert!(listener.wait_timeout(Duration::from_millis(10)).unwrap());
+
+        let mut connection = listener.accept().unwrap();
+        client.write(&mut b"ping".to_vec()).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(connection.read(&mut buf).unwrap(), b"ping");
+    }
+
+    #[test]
+    fn memory_acceptor() {
+        let transport = MemoryTransport::new();
+        let name = OsString::from("resident");
+        let mut acceptor = transport.inbound_acceptor(&name).unwrap();
+        assert!(transport.inbound_acceptor(&name).is_err());
+
+        for message in &[b"one", b"two"] {
+            let mut client = transport.open_outbound(&name).unwrap();
+            client.write(&mut message.to_vec()).unwrap();
+
+            let mut connection = acceptor.accept().unwrap();
+            let mut buf = [0; 16];
+            assert_eq!(connection.read(&mut buf).unwrap(), &message[..]);
+        }
+
+        drop(acceptor);
+        assert!(transport.open_outbound(&name).is_err());
+    }
+
+    #[test]
+    fn memory_read_all() {
+        let (mut ours, mut theirs) = MemoryConnection::pair();
+        let message = vec![7u8; 100];
+        ours.write(&mut message.clone()).unwrap();
+
+        let mut out = vec![1, 2, 3];
+        theirs.read_all(&mut out).unwrap();
+        assert_eq!(out, message);
+
+        drop(ours);
+        match theirs.read_all(&mut out) {
+            Err(Error::Disconnected) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match theirs.write(&mut b"hello".to_vec()) {
+            Err(Error::Disconnected) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,485 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use comical::error::{Error, Result};
+
+use transport::{
+    Acceptor, Listener, MessageRead, MessageWrite, PeerProcess, Transact, Transport, MAX_READ_ALL,
+};
+
+// Unix domain socket transport. Streams don't preserve message boundaries, so each message is
+// framed with a 4 byte little-endian length.
+//
+// Each socket is bound in its own directory, named by the name passed between client and server,
+// which only the owner can enter. So nobody else can connect, even in the moment after binding.
+
+#[derive(Clone)]
+pub struct UnixSocketTransport {
+    dir: PathBuf,
+}
+
+impl UnixSocketTransport {
+    /// Sockets are created in `dir`, the names passed between client and server are relative
+    /// to it.
+    pub fn new(dir: PathBuf) -> Self {
+        UnixSocketTransport { dir }
+    }
+
+    fn listener(&self) -> Result<UnixSocketListener> {
+        // Same naming scheme as the named pipes.
+        let name = OsString::from(format!("{:032x}", rand::random::<u128>()));
+        let path = self.dir.join(&name);
+        let listener = bind(&path)?;
+
+        Ok(UnixSocketListener {
+            name,
+            path,
+            listener,
+            ready: None,
+        })
+    }
+
+    fn open(&self, name: &OsStr) -> Result<UnixSocketConnection> {
+        let stream = UnixStream::connect(self.dir.join(name).join(SOCKET_NAME))
+            .map_err(|e| io_error("connect", e))?;
+        Ok(UnixSocketConnection { stream })
+    }
+}
+
+impl Default for UnixSocketTransport {
+    fn default() -> Self {
+        UnixSocketTransport::new(env::temp_dir())
+    }
+}
+
+impl Transport for UnixSocketTransport {
+    type DuplexListener = UnixSocketListener;
+    type DuplexConnection = UnixSocketConnection;
+    type InboundListener = UnixSocketListener;
+    type InboundConnection = UnixSocketConnection;
+    type InboundAcceptor = UnixSocketAcceptor;
+    type DuplexClient = UnixSocketConnection;
+    type OutboundClient = UnixSocketConnection;
+
+    fn duplex_listener(&self) -> Result<UnixSocketListener> {
+        self.listener()
+    }
+
+    fn inbound_listener(&self) -> Result<UnixSocketListener> {
+        self.listener()
+    }
+
+    fn inbound_acceptor(&self, name: &OsStr) -> Result<UnixSocketAcceptor> {
+        // Binding fails if the socket's directory already exists. If it was left behind by a
+        // server that crashed, clear it out and try again.
+        let path = self.dir.join(name);
+        let listener = match bind(&path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                if !is_stale(&path) {
+                    return Err(e);
+                }
+                unbind(&path);
+                bind(&path)?
+            }
+        };
+
+        Ok(UnixSocketAcceptor { path, listener })
+    }
+
+    fn open_duplex(&self, name: &OsStr) -> Result<UnixSocketConnection> {
+        self.open(name)
+    }
+
+    fn open_outbound(&self, name: &OsStr) -> Result<UnixSocketConnection> {
+        self.open(name)
+    }
+}
+
+pub struct UnixSocketListener {
+    name: OsString,
+    /// The socket's directory.
+    path: PathBuf,
+    listener: UnixListener,
+    /// Accepted by `wait_timeout`, for `accept` to return.
+    ready: Option<UnixStream>,
+}
+
+/// How often `wait_timeout` checks for a connection.
+const ACCEPT_POLL_MS: u64 = 10;
+
+impl UnixSocketListener {
+    fn poll_accept(&mut self, deadline: Instant) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    self.ready = Some(stream);
+                    return Ok(());
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(ACCEPT_POLL_MS)));
+        }
+    }
+}
+
+impl Listener for UnixSocketListener {
+    type Connection = UnixSocketConnection;
+
+    fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<bool> {
+        if self.ready.is_none() {
+            let deadline = Instant::now() + timeout;
+            self.listener
+                .set_nonblocking(true)
+                .map_err(|e| io_error("set_nonblocking", e))?;
+            let result = self.poll_accept(deadline);
+            self.listener
+                .set_nonblocking(false)
+                .map_err(|e| io_error("set_nonblocking", e))?;
+            result.map_err(|e| io_error("accept", e))?;
+        }
+        Ok(self.ready.is_some())
+    }
+
+    fn accept(mut self) -> Result<UnixSocketConnection> {
+        let stream = match self.ready.take() {
+            Some(stream) => stream,
+            None => self.listener.accept().map_err(|e| io_error("accept", e))?.0,
+        };
+        Ok(UnixSocketConnection { stream })
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        unbind(&self.path);
+    }
+}
+
+pub struct UnixSocketAcceptor {
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl Acceptor for UnixSocketAcceptor {
+    type Connection = UnixSocketConnection;
+
+    fn accept(&mut self) -> Result<UnixSocketConnection> {
+        let (stream, _) = self.listener.accept().map_err(|e| io_error("accept", e))?;
+        Ok(UnixSocketConnection { stream })
+    }
+}
+
+impl Drop for UnixSocketAcceptor {
+    fn drop(&mut self) {
+        unbind(&self.path);
+    }
+}
+
+pub struct UnixSocketConnection {
+    stream: UnixStream,
+}
+
+impl UnixSocketConnection {
+    fn read_header(&mut self) -> Result<usize> {
+        let mut header = [0u8; 4];
+        self.stream
+            .read_exact(&mut header)
+            .map_err(|e| io_error("read", e))?;
+        Ok((header[0] as usize)
+            | (header[1] as usize) << 8
+            | (header[2] as usize) << 16
+            | (header[3] as usize) << 24)
+    }
+
+    /// Skip the rest of a message that was refused, so the next read starts at a header.
+    fn skip(&mut self, len: usize) -> Result<()> {
+        let skipped = io::copy(&mut (&self.stream).take(len as u64), &mut io::sink())
+            .map_err(|e| io_error("read", e))?;
+        if skipped != len as u64 {
+            return Err(Error::Disconnected);
+        }
+        Ok(())
+    }
+}
+
+impl MessageRead for UnixSocketConnection {
+    fn read<'b>(&mut self, out_buf: &'b mut [u8]) -> Result<&'b mut [u8]> {
+        let len = self.read_header()?;
+        if len > out_buf.len() {
+            self.skip(len)?;
+            return Err(Error::Message(format!(
+                "message of {} bytes doesn't fit in {} byte buffer",
+                len,
+                out_buf.len()
+            )));
+        }
+
+        let out_buf = &mut out_buf[..len];
+        self.stream
+            .read_exact(out_buf)
+            .map_err(|e| io_error("read", e))?;
+        Ok(out_buf)
+    }
+
+    fn read_all(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        let len = self.read_header()?;
+        // Not skipped, that could take as long as the peer likes.
+        if len > MAX_READ_ALL {
+            return Err(Error::MessageTooLong {
+                len,
+                max: MAX_READ_ALL,
+            });
+        }
+
+        out.clear();
+        out.resize(len, 0);
+        self.stream.read_exact(out).map_err(|e| io_error("read", e))
+    }
+}
+
+impl MessageWrite for UnixSocketConnection {
+    fn write(&mut self, in_buf: &mut [u8]) -> Result<()> {
+        let len = in_buf.len();
+        if len > u32::max_value() as usize {
+            return Err(Error::Message(format!(
+                "message of {} bytes is too long",
+                len
+            )));
+        }
+
+        let header = [
+            len as u8,
+            (len >> 8) as u8,
+            (len >> 16) as u8,
+            (len >> 24) as u8,
+        ];
+        self.stream
+            .write_all(&header)
+            .and_then(|_| self.stream.write_all(in_buf))
+            .map_err(|e| io_error("write", e))
+    }
+}
+
+impl Transact for UnixSocketConnection {
+    fn transact(&mut self, in_buf: &mut [u8], out: &mut Vec<u8>) -> Result<()> {
+        self.write(in_buf)?;
+        self.read_all(out)
+    }
+}
+
+impl PeerProcess for UnixSocketConnection {
+    /// Not available from std, but the sockets are only open to their owner anyway.
+    fn peer_pid(&self) -> Result<Option<u32>> {
+        Ok(None)
+    }
+}
+
+/// The socket's file name, in its directory.
+const SOCKET_NAME: &str = "socket";
+
+/// Create the directory `path`, only accessible by the owner, and bind a socket in it. Fails if
+/// `path` already exists, so it can't be a directory someone else made.
+fn bind(path: &Path) -> Result<UnixListener> {
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(path)
+        .map_err(|e| io_error("create_dir", e))?;
+
+    UnixListener::bind(path.join(SOCKET_NAME)).map_err(|e| {
+        #[allow(unused_must_use)]
+        {
+            fs::remove_dir(path);
+        }
+        io_error("bind", e)
+    })
+}
+
+fn unbind(path: &Path) {
+    #[allow(unused_must_use)]
+    {
+        fs::remove_file(path.join(SOCKET_NAME));
+        fs::remove_dir(path);
+    }
+}
+
+/// Whether the socket directory `path` exists but nothing is listening in it. A live server
+/// accepts the connection, a dead one's socket refuses it, or is missing if the server died
+/// before binding.
+fn is_stale(path: &Path) -> bool {
+    if !path.is_dir() {
+        return false;
+    }
+    match UnixStream::connect(path.join(SOCKET_NAME)) {
+        Err(ref e) => {
+            e.kind() == io::ErrorKind::ConnectionRefused || e.kind() == io::ErrorKind::NotFound
+        }
+        Ok(_) => false,
+    }
+}
+
+fn io_error(op: &str, e: io::Error) -> Error {
+    match e.kind() {
+        // The stream ended, or the other end closed it while we were writing.
+        io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset => Error::Disconnected,
+        _ => Error::Message(format!("unix socket {} failed: {}", op, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+    use std::thread;
+
+    #[test]
+    fn framing() {
+        let transport = UnixSocketTransport::default();
+        let listener = transport.duplex_listener().unwrap();
+        let name = listener.name().to_os_string();
+
+        let server_transport = transport.clone();
+        let server = thread::spawn(move || {
+            let mut pipe = server_transport.open_duplex(&name).unwrap();
+            let mut buf = [0; 16];
+            for _ in 0..2 {
+                let mut request = pipe.read(&mut buf).unwrap().to_vec();
+                request.reverse();
+                pipe.write(&mut request).unwrap();
+            }
+        });
+
+        let mut connection = listener.accept().unwrap();
+        let mut response = Vec::new();
+        connection
+            .transact(&mut b"abc".to_vec(), &mut response)
+            .unwrap();
+        assert_eq!(response, b"cba");
+        connection.transact(&mut [], &mut response).unwrap();
+        assert_eq!(response, b"");
+        server.join().unwrap();
+
+        match connection.read_all(&mut response) {
+            Err(Error::Disconnected) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn private() {
+        let transport = UnixSocketTransport::default();
+        let listener = transport.duplex_listener().unwrap();
+        let path = env::temp_dir().join(listener.name());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn too_long() {
+        let transport = UnixSocketTransport::default();
+        let listener = transport.duplex_listener().unwrap();
+        let mut client = transport.open_duplex(listener.name()).unwrap();
+        let mut connection = listener.accept().unwrap();
+
+        client.write(&mut [0xff; 20]).unwrap();
+        client.write(&mut b"next".to_vec()).unwrap();
+        let mut buf = [0; 16];
+        assert!(connection.read(&mut buf).is_err());
+        assert_eq!(connection.read(&mut buf).unwrap(), b"next");
+    }
+
+    #[test]
+    fn read_all_too_long() {
+        let transport = UnixSocketTransport::default();
+        let listener = transport.duplex_listener().unwrap();
+        let client = transport.open_duplex(listener.name()).unwrap();
+        let mut connection = listener.accept().unwrap();
+
+        // Only the header, claiming far more than is allowed.
+        (&client.stream).write_all(&[0xff; 4]).unwrap();
+        let mut buf = Vec::new();
+        match connection.read_all(&mut buf) {
+            Err(Error::MessageTooLong { len, max }) => {
+                assert_eq!(len, 0xffff_ffff);
+                assert_eq!(max, MAX_READ_ALL);
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(buf.capacity() < MAX_READ_ALL);
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let transport = UnixSocketTransport::default();
+        let mut listener = transport.duplex_listener().unwrap();
+        assert!(!listener.wait_timeout(Duration::from_millis(20)).unwrap());
+
+        let mut client = transport.open_duplex(listener.name()).unwrap();
+        assert!(listener.wait_timeout(Duration::from_millis(20)).unwrap());
+
+        let mut connection = listener.accept().unwrap();
+        client.write(&mut b"ping".to_vec()).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(connection.read(&mut buf).unwrap(), b"ping");
+    }
+
+    #[test]
+    fn acceptor() {
+        let transport = UnixSocketTransport::default();
+        let name = OsString::from(format!("{:032x}", rand::random::<u128>()));
+        let mut acceptor = transport.inbound_acceptor(&name).unwrap();
+        assert!(transport.inbound_acceptor(&name).is_err());
+        // The check that the socket is live connects to it.
+        acceptor.accept().unwrap();
+
+        for message in &[b"one", b"two"] {
+            let mut client = transport.open_outbound(&name).unwrap();
+            client.write(&mut message.to_vec()).unwrap();
+
+            let mut connection = acceptor.accept().unwrap();
+            let mut buf = [0; 16];
+            assert_eq!(connection.read(&mut buf).unwrap(), &message[..]);
+        }
+    }
+
+    #[test]
+    fn stale_acceptor() {
+        let transport = UnixSocketTransport::default();
+        let name = OsString::from(format!("{:032x}", rand::random::<u128>()));
+
+        // A crashed server leaves its socket bound but not listening.
+        drop(bind(&transport.dir.join(&name)).unwrap());
+        assert!(transport.dir.join(&name).join(SOCKET_NAME).exists());
+
+        let mut acceptor = transport.inbound_acceptor(&name).unwrap();
+        let mut client = transport.open_outbound(&name).unwrap();
+        client.write(&mut b"ping".to_vec()).unwrap();
+        let mut connection = acceptor.accept().unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(connection.read(&mut buf).unwrap(), b"ping");
+    }
+}